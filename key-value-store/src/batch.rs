@@ -0,0 +1,137 @@
+/// A group of sets and removes that `KvsEngine::write_batch` applies atomically:
+/// after a crash either every command in the batch is visible or none is.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    commands: Vec<crate::utility::WriteCommand>,
+    on_existing: OnExisting,
+}
+
+/// What `KvsEngine::write_batch` does with a set of a key that already holds a live
+/// value in the store.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnExisting {
+    /// Replace the stored value.
+    #[default]
+    Overwrite,
+    /// Fail with `KvsError::KeyExists` before writing anything.
+    Fail,
+    /// Keep the stored value and leave the set out.
+    Skip,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.commands
+            .push(crate::utility::WriteCommand::Set(key.into(), value.into()));
+        self
+    }
+
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: std::time::Duration,
+    ) -> &mut WriteBatch {
+        self.commands
+            .push(crate::utility::WriteCommand::SetWithExpiry(
+                key.into(),
+                value.into(),
+                crate::utility::expiry_after(ttl),
+            ));
+        self
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.commands
+            .push(crate::utility::WriteCommand::Remove(key.into()));
+        self
+    }
+
+    pub fn push(&mut self, command: crate::utility::WriteCommand) -> &mut WriteBatch {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn on_existing(&mut self, on_existing: OnExisting) -> &mut WriteBatch {
+        self.on_existing = on_existing;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub fn commands(&self) -> &[crate::utility::WriteCommand] {
+        &self.commands
+    }
+
+    pub fn into_commands(self) -> Vec<crate::utility::WriteCommand> {
+        self.commands
+    }
+
+    // Applies `on_existing` to the sets of keys that are in the store according to
+    // `exists`. Keys set earlier in the batch do not count as existing.
+    pub(crate) fn check_existing(
+        &mut self,
+        mut exists: impl FnMut(&[u8]) -> crate::utility::Result<bool>,
+    ) -> crate::utility::Result<()> {
+        if self.on_existing == OnExisting::Overwrite {
+            return Ok(());
+        }
+        let mut kept = Vec::with_capacity(self.commands.len());
+        for command in std::mem::take(&mut self.commands) {
+            let existing = match &command {
+                crate::utility::WriteCommand::Set(key, _)
+                | crate::utility::WriteCommand::SetWithExpiry(key, _, _) => exists(key)?,
+                crate::utility::WriteCommand::Remove(_) => false,
+            };
+            match (existing, self.on_existing) {
+                (false, _) => kept.push(command),
+                (true, OnExisting::Fail) => {
+                    return Err(crate::utility::KvsError::KeyExists(command.into_key()))
+                }
+                (true, _) => {}
+            }
+        }
+        self.commands = kept;
+        Ok(())
+    }
+
+    // Fails with `KeyNotFound` if a remove targets a key that is neither in the store,
+    // according to `exists`, nor set earlier in the batch.
+    pub(crate) fn check_removes(
+        &self,
+        mut exists: impl FnMut(&[u8]) -> crate::utility::Result<bool>,
+    ) -> crate::utility::Result<()> {
+        let mut pending = std::collections::HashMap::new();
+        for command in &self.commands {
+            match command {
+                crate::utility::WriteCommand::Set(key, _) => {
+                    pending.insert(key.as_slice(), true);
+                }
+                crate::utility::WriteCommand::SetWithExpiry(key, _, expires_at) => {
+                    pending.insert(key.as_slice(), !crate::utility::is_expired(*expires_at));
+                }
+                crate::utility::WriteCommand::Remove(key) => {
+                    let present = match pending.get(key.as_slice()) {
+                        Some(present) => *present,
+                        None => exists(key)?,
+                    };
+                    if !present {
+                        return Err(crate::utility::KvsError::KeyNotFound);
+                    }
+                    pending.insert(key.as_slice(), false);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+extern crate clap;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let addr_arg = || {
+        clap::Arg::with_name("ADDR")
+            .long("addr")
+            .help("Address of the server, as IP:PORT")
+            .takes_value(true)
+            .default_value(kvs::protocol::DEFAULT_ADDR)
+    };
+    let matches = clap::App::new("kvs-client")
+        .version(clap::crate_version!())
+        .author(clap::crate_authors!())
+        .about("Talks to a kvs-server")
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::SubCommand::with_name("set")
+                .about("Set the key to a value")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("value")
+                        .help("The value")
+                        .takes_value(true)
+                        .required(true)
+                        .index(2),
+                )
+                .arg(addr_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("get")
+                .about("Get the value set by key")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(addr_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("rm")
+                .about("Remove a key value")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(addr_arg()),
+        )
+        .get_matches();
+
+    let (name, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let mut client = kvs::client::KvsClient::connect(matches.value_of("ADDR").unwrap())?;
+    let key = matches.value_of("key").unwrap();
+
+    match name {
+        "set" => {
+            let value = matches.value_of("value").unwrap();
+            client.set(key.into(), value.into())?;
+        }
+        "get" => match client.get(key.into())? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        "rm" => match client.remove(key.into()) {
+            Err(kvs::utility::KvsError::KeyNotFound) => {
+                println!("Key not found");
+                std::process::exit(1);
+            }
+            result => result?,
+        },
+        _ => unimplemented!("No supported command provided"),
+    }
+    Ok(())
+}
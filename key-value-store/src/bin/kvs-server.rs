@@ -0,0 +1,57 @@
+extern crate clap;
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let matches = clap::App::new("kvs-server")
+        .version(clap::crate_version!())
+        .author(clap::crate_authors!())
+        .about("Serves a key/value store over TCP")
+        .arg(
+            clap::Arg::with_name("ADDR")
+                .long("addr")
+                .help("Address to listen on, as IP:PORT")
+                .takes_value(true)
+                .default_value(kvs::protocol::DEFAULT_ADDR),
+        )
+        .arg(
+            clap::Arg::with_name("LOG_PATH")
+                .long("path")
+                .help("Path to log folders")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            clap::Arg::with_name("ENGINE")
+                .long("engine")
+                .help("Storage engine to use")
+                .takes_value(true)
+                .possible_values(&["kvs", "memory"])
+                .default_value("kvs"),
+        )
+        .get_matches();
+
+    let path = matches
+        .value_of("LOG_PATH")
+        .map(std::path::PathBuf::from)
+        .unwrap_or(".".into());
+    let addr = matches.value_of("ADDR").unwrap();
+    let engine: kvs::engine::Engine = matches.value_of("ENGINE").unwrap().parse()?;
+
+    let keystore = engine.open(path, kvs::kvs::Durability::default())?;
+    log::info!(
+        "kvs-server {} listening on {} with engine {}",
+        clap::crate_version!(),
+        addr,
+        engine
+    );
+    kvs::server::KvsServer::new(keystore.into()).run(addr)?;
+    Ok(())
+}
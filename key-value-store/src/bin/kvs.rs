@@ -1,16 +1,50 @@
 extern crate clap;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     let matches = clap::App::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
         .about(clap::crate_description!())
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
         .arg(
             clap::Arg::with_name("LOG_PATH")
                 .long("path")
                 .help("Path to log folders")
                 .takes_value(true)
-                .required(false),
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("ENGINE")
+                .long("engine")
+                .help("Storage engine to use")
+                .takes_value(true)
+                .possible_values(&["kvs", "memory"])
+                .default_value("kvs"),
+        )
+        .arg(
+            clap::Arg::with_name("DURABILITY")
+                .long("durability")
+                .help("When to sync writes to disk")
+                .takes_value(true)
+                .possible_values(&["always", "interval", "never"])
+                .default_value("never"),
+        )
+        .arg(
+            clap::Arg::with_name("SYNC_INTERVAL")
+                .long("sync-interval")
+                .help("Milliseconds between syncs with --durability interval")
+                .takes_value(true)
+                .default_value("1000"),
         )
         .subcommand(
             clap::SubCommand::with_name("set")
@@ -26,8 +60,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     clap::Arg::with_name("value")
                         .help("The value")
                         .takes_value(true)
-                        .required(true)
+                        .required_unless("file")
                         .index(2),
+                )
+                .arg(
+                    clap::Arg::with_name("file")
+                        .long("file")
+                        .help("Read the value from this file, or from stdin if it is -")
+                        .takes_value(true)
+                        .conflicts_with("value"),
                 ),
         )
         .subcommand(
@@ -52,27 +93,335 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .index(1),
                 ),
         )
+        .subcommand(
+            clap::SubCommand::with_name("cas")
+                .about("Replace the value of a key if it still has the expected value")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("expected")
+                        .long("expected")
+                        .help("The current value, or leave out if the key must be absent")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("new")
+                        .long("new")
+                        .help("The new value, or leave out to remove the key")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set-if-absent")
+                .about("Set the key to a value unless it exists")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("value")
+                        .help("The value")
+                        .takes_value(true)
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("set-if-present")
+                .about("Set the key to a value only if it exists")
+                .arg(
+                    clap::Arg::with_name("key")
+                        .help("The key")
+                        .takes_value(true)
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("value")
+                        .help("The value")
+                        .takes_value(true)
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("scan")
+                .about("List key values in key order")
+                .arg(
+                    clap::Arg::with_name("prefix")
+                        .long("prefix")
+                        .help("Only list keys starting with this prefix")
+                        .takes_value(true)
+                        .default_value(""),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Verify every record in the log files without modifying them")
+                .arg(
+                    clap::Arg::with_name("salvage")
+                        .long("salvage")
+                        .help("Copy every readable record into a new store in this directory")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Write every key value to stdout as JSON Lines"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Load key values written by export")
+                .arg(
+                    clap::Arg::with_name("file")
+                        .help("The dump to load, or - for stdin")
+                        .takes_value(true)
+                        .default_value("-")
+                        .index(1),
+                )
+                .arg(
+                    clap::Arg::with_name("overwrite")
+                        .long("overwrite")
+                        .help("Replace the values of keys that already exist"),
+                )
+                .arg(
+                    clap::Arg::with_name("skip-existing")
+                        .long("skip-existing")
+                        .help("Keep the values of keys that already exist")
+                        .conflicts_with("overwrite"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("stats")
+                .about("Show how many keys the store holds and how much space it takes")
+                .arg(
+                    clap::Arg::with_name("json")
+                        .long("json")
+                        .help("Print the statistics as a JSON object"),
+                ),
+        )
         .get_matches();
 
     let path = matches
         .value_of("LOG_PATH")
-        .map(|p| std::path::PathBuf::from(p))
+        .map(std::path::PathBuf::from)
         .unwrap_or(".".into());
 
-    let mut keystore = kvs::kvs::KvStore::open(path)?;
+    let durability = match matches.value_of("DURABILITY") {
+        Some("always") => kvs::kvs::Durability::Always,
+        Some("interval") => kvs::kvs::Durability::Interval(std::time::Duration::from_millis(
+            matches.value_of("SYNC_INTERVAL").unwrap().parse()?,
+        )),
+        _ => kvs::kvs::Durability::Never,
+    };
+
+    // Checks read the log files directly, so they work on stores that fail to open.
+    if let Some(matches) = matches.subcommand_matches("check") {
+        check(&path, matches.value_of("salvage"))?;
+        return Ok(());
+    }
+
+    let engine: kvs::engine::Engine = matches.value_of("ENGINE").unwrap().parse()?;
+    // Reads do not need the exclusive lock, so they also work while another
+    // process has the store open.
+    let keystore = if matches!(
+        matches.subcommand_name(),
+        Some("get") | Some("scan") | Some("stats") | Some("export")
+    ) {
+        engine.open_read_only(path)?
+    } else {
+        engine.open(path, durability)?
+    };
 
     if let Some(matches) = matches.subcommand_matches("set") {
         let key = matches.value_of("key").unwrap();
-        let value = matches.value_of("value").unwrap();
-        keystore.set(key.into(), value.into())?;
+        let value = match matches.value_of("file") {
+            Some("-") => {
+                let mut value = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut value)?;
+                value
+            }
+            Some(file) => std::fs::read(file)?,
+            None => matches.value_of("value").unwrap().into(),
+        };
+        keystore.set_bytes(key.as_bytes(), &value)?;
     } else if let Some(matches) = matches.subcommand_matches("get") {
         let key = matches.value_of("key").unwrap();
-        keystore.get(key.into())?;
+        match keystore.get_bytes(key.as_bytes())? {
+            Some(value) => {
+                let mut stdout = std::io::stdout();
+                std::io::Write::write_all(&mut stdout, &value)?;
+                std::io::Write::write_all(&mut stdout, b"\n")?;
+            }
+            None => println!("Key not found"),
+        }
     } else if let Some(matches) = matches.subcommand_matches("rm") {
         let key = matches.value_of("key").unwrap();
-        keystore.remove(key.into())?;
+        match keystore.remove(key.into()) {
+            Err(kvs::utility::KvsError::KeyNotFound) => {
+                println!("Key not found");
+                drop(keystore);
+                std::process::exit(1);
+            }
+            result => result?,
+        }
+    } else if let Some(matches) = matches.subcommand_matches("cas") {
+        let key = matches.value_of("key").unwrap();
+        let expected = matches.value_of("expected").map(String::from);
+        let new = matches.value_of("new").map(String::from);
+        if !keystore.compare_and_swap(key.into(), expected, new)? {
+            precondition_failed(keystore);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("set-if-absent") {
+        let key = matches.value_of("key").unwrap();
+        let value = matches.value_of("value").unwrap();
+        if !keystore.set_if_absent(key.into(), value.into())? {
+            precondition_failed(keystore);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("set-if-present") {
+        let key = matches.value_of("key").unwrap();
+        let value = matches.value_of("value").unwrap();
+        if !keystore.set_if_present(key.into(), value.into())? {
+            precondition_failed(keystore);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("scan") {
+        let prefix = matches.value_of("prefix").unwrap();
+        for (key, value) in keystore.scan_prefix_bytes(prefix.as_bytes())? {
+            println!(
+                "{}\t{}",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&value)
+            );
+        }
+    } else if matches.subcommand_matches("export").is_some() {
+        let stdout = std::io::stdout();
+        kvs::dump::export(&*keystore, std::io::BufWriter::new(stdout.lock()))?;
+    } else if let Some(matches) = matches.subcommand_matches("import") {
+        let on_existing = if matches.is_present("overwrite") {
+            kvs::batch::OnExisting::Overwrite
+        } else if matches.is_present("skip-existing") {
+            kvs::batch::OnExisting::Skip
+        } else {
+            kvs::batch::OnExisting::Fail
+        };
+        match matches.value_of("file").unwrap() {
+            "-" => kvs::dump::import(&*keystore, std::io::stdin().lock(), on_existing)?,
+            file => kvs::dump::import(
+                &*keystore,
+                std::io::BufReader::new(std::fs::File::open(file)?),
+                on_existing,
+            )?,
+        };
+    } else if let Some(matches) = matches.subcommand_matches("stats") {
+        let stats = keystore.stats()?;
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string(&stats)?);
+        } else {
+            print_stats(&stats);
+        }
     } else {
         unimplemented!("No supported command provided")
     }
     Ok(())
 }
+
+// Prints every record and damaged region, then the totals, and exits with 1 if the
+// store is corrupted.
+fn check(path: &std::path::Path, salvage: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let files = kvs::check::check_store(path)?;
+    let mut corrupted = false;
+    let mut records = 0;
+    let mut damaged = 0;
+    let mut key_records = std::collections::HashMap::<&[u8], usize>::new();
+    for (idx, file) in files.iter().enumerate() {
+        let name = file.path.display();
+        for entry in &file.entries {
+            match entry {
+                kvs::check::Entry::Record {
+                    offset,
+                    len,
+                    record,
+                } => {
+                    records += 1;
+                    let description = match record {
+                        kvs::utility::Record::Command(command) => {
+                            *key_records.entry(command.key()).or_default() += 1;
+                            let kind = match command {
+                                kvs::utility::WriteCommand::Set(..) => "set",
+                                kvs::utility::WriteCommand::SetWithExpiry(..) => "set-with-expiry",
+                                kvs::utility::WriteCommand::Remove(_) => "remove",
+                            };
+                            format!("{}\t{}", kind, String::from_utf8_lossy(command.key()))
+                        }
+                        kvs::utility::Record::BatchBegin(count) => {
+                            format!("batch-begin\t{}", count)
+                        }
+                        kvs::utility::Record::BatchCommit(count) => {
+                            format!("batch-commit\t{}", count)
+                        }
+                    };
+                    println!("{}:{}\t{}\t{}", name, offset, len, description);
+                }
+                kvs::check::Entry::Unreadable { offset, len } => {
+                    damaged += 1;
+                    println!("{}:{}\t{}\tunreadable", name, offset, len);
+                }
+                kvs::check::Entry::Torn { offset, len } => {
+                    damaged += 1;
+                    println!("{}:{}\t{}\ttorn write", name, offset, len);
+                }
+            }
+        }
+        corrupted |= file.is_corrupted(idx + 1 == files.len());
+    }
+    let duplicate_keys = key_records.values().filter(|count| **count > 1).count();
+    let superseded: usize = key_records.values().map(|count| count - 1).sum();
+    println!("log files:        {}", files.len());
+    println!("records:          {}", records);
+    println!("damaged regions:  {}", damaged);
+    println!("keys:             {}", key_records.len());
+    println!(
+        "duplicate keys:   {} ({} superseded records)",
+        duplicate_keys, superseded
+    );
+    if let Some(dest) = salvage {
+        let keys = kvs::check::salvage(&files, std::path::Path::new(dest))?;
+        println!("salvaged {} keys into {}", keys, dest);
+    }
+    if corrupted {
+        println!("Corruption found");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn print_stats(stats: &kvs::engine::Stats) {
+    println!("live keys:       {}", stats.live_keys);
+    println!("tombstones:      {}", stats.tombstones);
+    println!("total bytes:     {}", stats.total_bytes);
+    println!("wasted bytes:    {}", stats.wasted_bytes);
+    println!("log files:       {}", stats.log_files);
+    match stats.last_compaction {
+        Some(finished_at) => println!(
+            "last compaction: {}s ago",
+            kvs::utility::unix_millis().saturating_sub(finished_at) / 1000
+        ),
+        None => println!("last compaction: never"),
+    }
+}
+
+// Conditional writes that were refused exit with 2, so scripts can tell them
+// apart from a missing key or any other failure, which exit with 1.
+fn precondition_failed(keystore: Box<dyn kvs::engine::KvsEngine>) -> ! {
+    println!("Precondition failed");
+    drop(keystore);
+    std::process::exit(2);
+}
@@ -0,0 +1,151 @@
+//! Offline integrity checks for the log files of a `KvStore`, behind `kvs check`.
+//!
+//! Unlike `KvStore::open`, which stops at the first bad record, the checker keeps
+//! going past unreadable regions by looking for the next record that decodes, so
+//! it can report every problem in a file and salvage everything around them.
+
+/// What the checker found at one position in a log file.
+#[derive(Debug)]
+pub enum Entry {
+    /// A record that decoded and passed its checksum.
+    Record {
+        offset: u64,
+        len: u64,
+        record: crate::utility::Record,
+    },
+    /// Bytes no record could be decoded from, up to the next readable record.
+    Unreadable { offset: u64, len: u64 },
+    /// A record cut short by the end of the file, as a crash during a write leaves
+    /// behind. `KvStore::open` drops these from the newest log file.
+    Torn { offset: u64, len: u64 },
+}
+
+/// The entries of one log file, in file order.
+#[derive(Debug)]
+pub struct FileCheck {
+    pub log_id: u64,
+    pub path: std::path::PathBuf,
+    pub entries: Vec<Entry>,
+}
+
+impl FileCheck {
+    /// Whether the file holds anything the store could not read back, not counting
+    /// a torn write at the end of the newest log file.
+    pub fn is_corrupted(&self, newest: bool) -> bool {
+        self.entries.iter().any(|entry| match entry {
+            Entry::Record { .. } => false,
+            Entry::Unreadable { .. } => true,
+            Entry::Torn { .. } => !newest,
+        })
+    }
+}
+
+/// Checks every log file of the store in `path`, oldest first. These are the
+/// generations listed in its manifest, or every log file in the directory for a
+/// store without one. Nothing in `path` is modified.
+pub fn check_store(path: &std::path::Path) -> crate::utility::Result<Vec<FileCheck>> {
+    let log_ids = match crate::manifest::Manifest::load(path)? {
+        Some(manifest) => manifest.generations,
+        None => crate::utility::sorted_log_ids(&path.to_path_buf())?,
+    };
+    log_ids
+        .into_iter()
+        .map(|log_id| {
+            let file = crate::utility::log_path(path, log_id);
+            Ok(FileCheck {
+                log_id,
+                entries: check_log(&file)?,
+                path: file,
+            })
+        })
+        .collect()
+}
+
+/// Walks the log file at `path` record by record.
+pub fn check_log(path: &std::path::Path) -> crate::utility::Result<Vec<Entry>> {
+    let bytes = std::fs::read(path)?;
+    let mut reader = crate::utility::BufReaderWithPos::new(std::io::Cursor::new(&bytes[..]))?;
+    let mut entries = Vec::new();
+    loop {
+        let offset = reader.pos();
+        match crate::utility::read_record(&mut reader, path) {
+            Ok(Some(record)) => entries.push(Entry::Record {
+                offset,
+                len: reader.pos() - offset,
+                record,
+            }),
+            Ok(None) => break,
+            Err(crate::utility::KvsError::Io(err)) => return Err(err.into()),
+            Err(err @ crate::utility::KvsError::IncompatibleStore(_)) => return Err(err),
+            Err(err) => {
+                let next = crate::utility::next_record(&bytes, offset + 1, path);
+                let len = next.unwrap_or(bytes.len() as u64) - offset;
+                entries.push(match (err, next) {
+                    (crate::utility::KvsError::TruncatedLog { .. }, None) => {
+                        Entry::Torn { offset, len }
+                    }
+                    _ => Entry::Unreadable { offset, len },
+                });
+                match next {
+                    Some(next) => {
+                        std::io::Seek::seek(&mut reader, std::io::SeekFrom::Start(next))?;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Replays every readable set and remove in `files`, in order, into a new store in
+/// `dest`, leaving out keys that end up removed or expired. As when the store is
+/// opened, the commands of a batch are only salvaged if its commit marker is read
+/// and every command in between is readable. Returns the number of keys written.
+pub fn salvage(files: &[FileCheck], dest: &std::path::Path) -> crate::utility::Result<usize> {
+    let mut latest = std::collections::BTreeMap::new();
+    for file in files {
+        // The batch begun last, with the commands read since.
+        let mut batch: Option<(u32, Vec<&crate::utility::WriteCommand>)> = None;
+        for entry in &file.entries {
+            let record = match entry {
+                Entry::Record { record, .. } => record,
+                Entry::Unreadable { .. } | Entry::Torn { .. } => continue,
+            };
+            match record {
+                crate::utility::Record::Command(command) => match &mut batch {
+                    Some((_, pending)) => pending.push(command),
+                    None => {
+                        latest.insert(command.key().to_vec(), command);
+                    }
+                },
+                crate::utility::Record::BatchBegin(count) => batch = Some((*count, Vec::new())),
+                crate::utility::Record::BatchCommit(count) => match batch.take() {
+                    Some((begun, pending))
+                        if begun == *count && pending.len() == *count as usize =>
+                    {
+                        for command in pending {
+                            latest.insert(command.key().to_vec(), command);
+                        }
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+    let mut batch = crate::batch::WriteBatch::new();
+    for command in latest.into_values().filter(|command| command.is_live()) {
+        batch.push(command.clone());
+    }
+    if std::fs::read_dir(dest).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} is not empty", dest.display()),
+        )
+        .into());
+    }
+    let store = crate::kvs::KvStore::open(dest)?;
+    let keys = batch.len();
+    store.write_batch(batch)?;
+    Ok(keys)
+}
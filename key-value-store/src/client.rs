@@ -0,0 +1,73 @@
+use std::io::Write;
+
+/// A connection to a `kvs-server`.
+pub struct KvsClient {
+    responses: serde_json::StreamDeserializer<
+        'static,
+        serde_json::de::IoRead<std::io::BufReader<std::net::TcpStream>>,
+        crate::protocol::Response,
+    >,
+    writer: std::io::BufWriter<std::net::TcpStream>,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl std::net::ToSocketAddrs) -> crate::utility::Result<KvsClient> {
+        let stream = std::net::TcpStream::connect(addr)?;
+        let reader = std::io::BufReader::new(stream.try_clone()?);
+        Ok(KvsClient {
+            responses: serde_json::Deserializer::from_reader(reader).into_iter(),
+            writer: std::io::BufWriter::new(stream),
+        })
+    }
+
+    pub fn get(&mut self, key: String) -> crate::utility::Result<Option<String>> {
+        match self.request(crate::protocol::Request::Get(key.into_bytes()))? {
+            crate::protocol::Response::Value(value) => {
+                value.map(crate::utility::into_string).transpose()
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> crate::utility::Result<()> {
+        match self.request(crate::protocol::Request::Write(
+            crate::utility::WriteCommand::Set(key.into_bytes(), value.into_bytes()),
+        ))? {
+            crate::protocol::Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub fn remove(&mut self, key: String) -> crate::utility::Result<()> {
+        match self.request(crate::protocol::Request::Write(
+            crate::utility::WriteCommand::Remove(key.into_bytes()),
+        ))? {
+            crate::protocol::Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(
+        &mut self,
+        request: crate::protocol::Request,
+    ) -> crate::utility::Result<crate::protocol::Response> {
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.flush()?;
+        match self.responses.next().transpose()? {
+            Some(crate::protocol::Response::KeyNotFound) => {
+                Err(crate::utility::KvsError::KeyNotFound)
+            }
+            Some(crate::protocol::Response::Error(message)) => {
+                Err(crate::utility::KvsError::Server(message))
+            }
+            Some(response) => Ok(response),
+            None => Err(crate::utility::KvsError::Server(
+                "connection closed by server".to_owned(),
+            )),
+        }
+    }
+}
+
+fn unexpected(response: crate::protocol::Response) -> crate::utility::KvsError {
+    crate::utility::KvsError::Server(format!("unexpected response {:?}", response))
+}
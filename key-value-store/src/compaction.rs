@@ -0,0 +1,52 @@
+/// How much space the log files of a store take up, as seen by a `CompactionPolicy`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// The size of every log file together.
+    pub total_bytes: u64,
+    /// The bytes taken up by records a compaction would drop.
+    pub wasted_bytes: u64,
+    /// The number of log files that no longer take writes.
+    pub sealed_segments: usize,
+}
+
+/// Decides after each write whether `KvStore` should start a background compaction.
+pub trait CompactionPolicy: std::fmt::Debug + Send + Sync {
+    fn should_compact(&self, usage: &SpaceUsage) -> bool;
+}
+
+/// Compacts once more than this fraction of the log is garbage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WasteRatio(pub f64);
+
+impl Default for WasteRatio {
+    fn default() -> WasteRatio {
+        WasteRatio(0.25)
+    }
+}
+
+impl CompactionPolicy for WasteRatio {
+    fn should_compact(&self, usage: &SpaceUsage) -> bool {
+        usage.total_bytes > 0 && usage.wasted_bytes as f64 / usage.total_bytes as f64 > self.0
+    }
+}
+
+/// Compacts once more than this many bytes of the log are garbage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GarbageBytes(pub u64);
+
+impl CompactionPolicy for GarbageBytes {
+    fn should_compact(&self, usage: &SpaceUsage) -> bool {
+        usage.wasted_bytes > self.0
+    }
+}
+
+/// Compacts once there are more than this many sealed log files, which segment
+/// rollover keeps adding to even when nothing is overwritten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealedSegments(pub usize);
+
+impl CompactionPolicy for SealedSegments {
+    fn should_compact(&self, usage: &SpaceUsage) -> bool {
+        usage.sealed_segments > self.0
+    }
+}
@@ -0,0 +1,101 @@
+//! Dumps of a whole store as JSON Lines, behind `kvs export` and `kvs import`.
+//!
+//! Every line holds one live key/value pair. Keys and values that are valid UTF-8
+//! are written as JSON strings and anything else as an array of bytes:
+//!
+//! ```json
+//! {"key":"user:1","value":"alice"}
+//! {"key":"blob","value":[0,159,146,150]}
+//! ```
+
+/// A key or value in a dump.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data {
+        match String::from_utf8(bytes) {
+            Ok(text) => Data::Text(text),
+            Err(err) => Data::Bytes(err.into_bytes()),
+        }
+    }
+}
+
+impl From<Data> for Vec<u8> {
+    fn from(data: Data) -> Vec<u8> {
+        match data {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// One line of a dump.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Entry {
+    pub key: Data,
+    pub value: Data,
+}
+
+// How many keys `export` takes from the engine at a time.
+const EXPORT_CHUNK: usize = 1024;
+
+/// Writes every live key/value pair in `engine` to `writer`, in key order. Keys are
+/// taken a chunk at a time and values read one by one, so the engine is never held
+/// up for the whole export, and writes made meanwhile may or may not show up in
+/// the dump. Returns the number of pairs written.
+pub fn export(
+    engine: &dyn crate::engine::KvsEngine,
+    mut writer: impl std::io::Write,
+) -> crate::utility::Result<usize> {
+    let mut pairs = 0;
+    let mut start = std::ops::Bound::Unbounded;
+    loop {
+        let keys = engine.scan_keys(start, std::ops::Bound::Unbounded, EXPORT_CHUNK)?;
+        for key in &keys {
+            // The key may have been removed since its chunk was taken.
+            let value = match engine.get_bytes(key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            let entry = Entry {
+                key: key.clone().into(),
+                value: value.into(),
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+            pairs += 1;
+        }
+        match keys.into_iter().last() {
+            Some(last) => start = std::ops::Bound::Excluded(last),
+            None => break,
+        }
+    }
+    writer.flush()?;
+    Ok(pairs)
+}
+
+/// Loads the dump in `reader` into `engine` as a single write batch followed by a
+/// compaction, so a dump is imported entirely or not at all. Keys that already
+/// exist in the store are handled according to `on_existing` as the batch is
+/// committed. Later lines win over earlier ones with the same key. Returns the
+/// number of pairs written.
+pub fn import(
+    engine: &dyn crate::engine::KvsEngine,
+    reader: impl std::io::Read,
+    on_existing: crate::batch::OnExisting,
+) -> crate::utility::Result<usize> {
+    let mut batch = crate::batch::WriteBatch::new();
+    batch.on_existing(on_existing);
+    for entry in serde_json::Deserializer::from_reader(reader).into_iter::<Entry>() {
+        let entry = entry?;
+        batch.set(Vec::from(entry.key), Vec::from(entry.value));
+    }
+    let pairs = engine.write_batch(batch)?;
+    engine.compact()?;
+    Ok(pairs)
+}
@@ -0,0 +1,261 @@
+/// The operations every storage engine behind the `kvs` tools supports.
+///
+/// Engines synchronise internally, so one instance can be shared between threads,
+/// e.g. behind an `Arc`.
+pub trait KvsEngine: Send + Sync {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<()>;
+
+    /// Sets `key` to `value` until `expires_at`, in milliseconds since the Unix
+    /// epoch, after which the key reads as absent.
+    fn set_bytes_with_expiry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::utility::Result<()>;
+
+    fn get_bytes(&self, key: &[u8]) -> crate::utility::Result<Option<Vec<u8>>>;
+
+    fn remove_bytes(&self, key: &[u8]) -> crate::utility::Result<()>;
+
+    /// Replaces the value of `key` with `new` if its current value is `expected`,
+    /// where `None` stands for an absent key. Returns whether the write happened.
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool>;
+
+    fn set_bytes_if_absent(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool>;
+
+    fn set_bytes_if_present(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool>;
+
+    /// Returns the number of commands written, which leaves out sets skipped
+    /// through `WriteBatch::on_existing`.
+    fn write_batch(&self, batch: crate::batch::WriteBatch) -> crate::utility::Result<usize>;
+
+    /// Returns the live key/value pairs with keys between `start` and `end`, in
+    /// lexicographic key order.
+    fn scan_bytes(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+    ) -> crate::utility::Result<ByteScan>;
+
+    /// Returns up to `limit` live keys between `start` and `end`, in lexicographic
+    /// order, without reading their values.
+    fn scan_keys(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+        limit: usize,
+    ) -> crate::utility::Result<Vec<Vec<u8>>>;
+
+    fn stats(&self) -> crate::utility::Result<Stats>;
+
+    /// Reclaims the space taken by overwritten and removed data right away, where the
+    /// engine keeps any.
+    fn compact(&self) -> crate::utility::Result<()>;
+
+    fn set_bytes_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> crate::utility::Result<()> {
+        self.set_bytes_with_expiry(key, value, crate::utility::expiry_after(ttl))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: &[u8]) -> crate::utility::Result<ByteScan> {
+        let (start, end) = prefix_range(prefix);
+        self.scan_bytes(start, end)
+    }
+
+    // The string-based methods below wrap the byte-based ones and fail with
+    // `KvsError::NotUtf8` when a stored key or value is not valid UTF-8.
+
+    fn set(&self, key: String, value: String) -> crate::utility::Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: std::time::Duration,
+    ) -> crate::utility::Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    fn get(&self, key: String) -> crate::utility::Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(crate::utility::into_string)
+            .transpose()
+    }
+
+    fn remove(&self, key: String) -> crate::utility::Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> crate::utility::Result<bool> {
+        self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> crate::utility::Result<bool> {
+        self.set_bytes_if_absent(key.as_bytes(), value.as_bytes())
+    }
+
+    fn set_if_present(&self, key: String, value: String) -> crate::utility::Result<bool> {
+        self.set_bytes_if_present(key.as_bytes(), value.as_bytes())
+    }
+
+    fn scan(
+        &self,
+        start: std::ops::Bound<String>,
+        end: std::ops::Bound<String>,
+    ) -> crate::utility::Result<Scan> {
+        into_string_scan(
+            self.scan_bytes(start.map(String::into_bytes), end.map(String::into_bytes))?,
+        )
+    }
+
+    fn scan_prefix(&self, prefix: &str) -> crate::utility::Result<Scan> {
+        into_string_scan(self.scan_prefix_bytes(prefix.as_bytes())?)
+    }
+}
+
+/// The key/value pairs returned by a scan.
+pub type Scan = std::vec::IntoIter<(String, String)>;
+
+/// The key/value pairs returned by a byte-based scan.
+pub type ByteScan = std::vec::IntoIter<(Vec<u8>, Vec<u8>)>;
+
+pub fn into_string_scan(scan: ByteScan) -> crate::utility::Result<Scan> {
+    Ok(scan
+        .map(|(key, value)| {
+            Ok((
+                crate::utility::into_string(key)?,
+                crate::utility::into_string(value)?,
+            ))
+        })
+        .collect::<crate::utility::Result<Vec<_>>>()?
+        .into_iter())
+}
+
+/// Whether no key can fall in `range`. This includes ranges that start past their
+/// end, which `BTreeMap::range` panics on.
+pub fn is_empty_range<T: Ord>(range: &impl std::ops::RangeBounds<T>) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (std::ops::Bound::Included(start), std::ops::Bound::Included(end)) => start > end,
+        (
+            std::ops::Bound::Included(start) | std::ops::Bound::Excluded(start),
+            std::ops::Bound::Included(end) | std::ops::Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+/// The range of keys that start with `prefix`.
+pub fn prefix_range(prefix: &[u8]) -> (std::ops::Bound<Vec<u8>>, std::ops::Bound<Vec<u8>>) {
+    // The first key past every key with this prefix bumps the last byte that can be
+    // bumped and drops everything after it.
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (
+                std::ops::Bound::Included(prefix.to_vec()),
+                std::ops::Bound::Excluded(end),
+            );
+        }
+    }
+    (
+        std::ops::Bound::Included(prefix.to_vec()),
+        std::ops::Bound::Unbounded,
+    )
+}
+
+/// A snapshot of how much an engine holds, from `KvsEngine::stats`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Stats {
+    pub live_keys: usize,
+    /// Keys that read as absent but still take up space, because they were removed
+    /// or have expired and no compaction has dropped them yet.
+    pub tombstones: usize,
+    pub total_bytes: u64,
+    /// The bytes a compaction would reclaim.
+    pub wasted_bytes: u64,
+    pub log_files: usize,
+    /// When a compaction last finished, in milliseconds since the Unix epoch.
+    pub last_compaction: Option<u64>,
+}
+
+/// The storage engines that can be picked at runtime, e.g. through `kvs --engine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Kvs,
+    Memory,
+}
+
+impl Engine {
+    pub const ALL: [Engine; 2] = [Engine::Kvs, Engine::Memory];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Memory => "memory",
+        }
+    }
+
+    pub fn open(
+        self,
+        path: impl Into<std::path::PathBuf>,
+        durability: crate::kvs::Durability,
+    ) -> crate::utility::Result<Box<dyn KvsEngine>> {
+        match self {
+            Engine::Kvs => Ok(Box::new(crate::kvs::KvStore::open_with_durability(
+                path, durability,
+            )?)),
+            Engine::Memory => Ok(Box::new(crate::memory::MemStore::new())),
+        }
+    }
+
+    /// Opens the engine in `path` for reads only, see `KvStore::open_read_only`.
+    pub fn open_read_only(
+        self,
+        path: impl Into<std::path::PathBuf>,
+    ) -> crate::utility::Result<Box<dyn KvsEngine>> {
+        match self {
+            Engine::Kvs => Ok(Box::new(crate::kvs::KvStore::open_read_only(path)?)),
+            Engine::Memory => Ok(Box::new(crate::memory::MemStore::new())),
+        }
+    }
+}
+
+impl std::str::FromStr for Engine {
+    type Err = crate::utility::KvsError;
+
+    fn from_str(name: &str) -> crate::utility::Result<Engine> {
+        Engine::ALL
+            .iter()
+            .copied()
+            .find(|engine| engine.name() == name)
+            .ok_or_else(|| crate::utility::KvsError::UnknownEngine(name.to_owned()))
+    }
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
@@ -1,116 +1,1079 @@
+type Index = std::collections::BTreeMap<Vec<u8>, crate::utility::LogPointer>;
+
+type FileIndex = std::collections::HashMap<Vec<u8>, crate::utility::LogPointer>;
+
+type Readers = std::collections::HashMap<u64, crate::utility::BufReaderWithPos<std::fs::File>>;
+
+type Space = std::collections::BTreeMap<u64, SegmentSpace>;
+
+/// A log-structured key/value store.
+///
+/// Cloning a `KvStore` is cheap and every clone works on the same data. Each clone
+/// keeps its own log file readers, so reads on different clones run in parallel,
+/// while writes from all clones are serialised on the active log file.
 pub struct KvStore {
-    // TODO: To remove the size of this hashmap, you can calculate the size lazily
-    index: std::collections::HashMap<String, (crate::utility::WriteCommand, u64)>,
+    shared: std::sync::Arc<Shared>,
+    // `None` for a store opened read-only.
+    writer: Option<std::sync::Arc<std::sync::Mutex<LogWriter>>>,
+    readers: std::sync::Mutex<Readers>,
+}
+
+struct Shared {
     log_path: std::path::PathBuf,
-    log_writer: crate::utility::BufWriterWithPos<std::fs::File>,
+    index: std::sync::RwLock<Index>,
+    // Every log file with a smaller id has been compacted away, so readers for
+    // them can be closed.
+    safe_point: std::sync::atomic::AtomicU64,
+    // Only rewritten by a writable store, and only while holding this lock.
+    manifest: std::sync::Mutex<crate::manifest::Manifest>,
+    // How many bytes each log file holds and how many of them are garbage.
+    space: std::sync::Mutex<Space>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct SegmentSpace {
     total_bytes: u64,
     wasted_bytes: u64,
+    // The bytes of indexed records that turn into garbage when they expire, by
+    // expiry time. They move over to `wasted_bytes` once that time has passed.
+    expiring: std::collections::BTreeMap<u64, u64>,
 }
 
-impl KvStore {
-    pub fn open(path: impl Into<std::path::PathBuf>) -> crate::utility::Result<KvStore> {
+impl SegmentSpace {
+    // A tombstone is garbage as soon as it is written, since compaction drops it.
+    fn insert(&mut self, pointer: &crate::utility::LogPointer) {
+        match pointer.kind {
+            crate::utility::RecordKind::Set => {}
+            crate::utility::RecordKind::SetWithExpiry(expires_at) => {
+                *self.expiring.entry(expires_at).or_default() += pointer.len;
+            }
+            crate::utility::RecordKind::Remove => self.wasted_bytes += pointer.len,
+        }
+    }
+
+    // Counts the record `pointer` points at as garbage, unless it is a tombstone or
+    // expired, which were counted then already.
+    fn waste(&mut self, pointer: &crate::utility::LogPointer) {
+        match pointer.kind {
+            crate::utility::RecordKind::Set => {}
+            crate::utility::RecordKind::SetWithExpiry(expires_at) => {
+                match self.expiring.get_mut(&expires_at) {
+                    Some(bytes) => {
+                        *bytes -= pointer.len;
+                        if *bytes == 0 {
+                            self.expiring.remove(&expires_at);
+                        }
+                    }
+                    None => return,
+                }
+            }
+            crate::utility::RecordKind::Remove => return,
+        }
+        self.wasted_bytes += pointer.len;
+    }
+
+    fn expire(&mut self, now: u64) {
+        let pending = self.expiring.split_off(&now.saturating_add(1));
+        let expired = std::mem::replace(&mut self.expiring, pending);
+        self.wasted_bytes += expired.values().sum::<u64>();
+    }
+}
+
+impl Shared {
+    // Locks the space accounting after counting the records that have expired since
+    // the last call as garbage.
+    fn space(&self) -> std::sync::MutexGuard<'_, Space> {
+        let mut space = self.space.lock().unwrap();
+        let now = crate::utility::unix_millis();
+        for segment in space.values_mut() {
+            segment.expire(now);
+        }
+        space
+    }
+}
+
+struct LogWriter {
+    shared: std::sync::Arc<Shared>,
+    log_id: u64,
+    log_writer: crate::utility::BufWriterWithPos<std::fs::File>,
+    compactor: Option<std::thread::JoinHandle<()>>,
+    durability: Durability,
+    max_segment_size: u64,
+    compaction_policy: std::sync::Arc<dyn crate::compaction::CompactionPolicy>,
+    syncer: Option<Syncer>,
+    // Holds the exclusive lock on the store until the last clone is dropped.
+    _lock_file: std::fs::File,
+}
+
+/// How hard `KvStore` tries to get an acknowledged write onto stable storage.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync the active log file after every write.
+    Always,
+    /// Sync the active log file from a background thread at this interval.
+    Interval(std::time::Duration),
+    /// Only flush writes to the operating system.
+    #[default]
+    Never,
+}
+
+/// Configures how `KvStore` is opened.
+#[derive(Debug, Clone)]
+pub struct KvStoreBuilder {
+    durability: Durability,
+    max_segment_size: u64,
+    compaction_policy: std::sync::Arc<dyn crate::compaction::CompactionPolicy>,
+}
+
+impl Default for KvStoreBuilder {
+    fn default() -> KvStoreBuilder {
+        KvStoreBuilder {
+            durability: Durability::default(),
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE,
+            compaction_policy: std::sync::Arc::new(crate::compaction::WasteRatio::default()),
+        }
+    }
+}
+
+/// The size the active log file may grow to before writes roll over to a new one.
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+impl KvStoreBuilder {
+    pub fn new() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    pub fn durability(&mut self, durability: Durability) -> &mut KvStoreBuilder {
+        self.durability = durability;
+        self
+    }
+
+    /// Seals the active log file and starts a new one once a write takes it past
+    /// `bytes`. A single write or batch is never split across files, so a file can
+    /// end up larger than this.
+    pub fn max_segment_size(&mut self, bytes: u64) -> &mut KvStoreBuilder {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Decides when writes start a background compaction, by default once more than
+    /// a quarter of the log is garbage.
+    pub fn compaction_policy(
+        &mut self,
+        policy: impl crate::compaction::CompactionPolicy + 'static,
+    ) -> &mut KvStoreBuilder {
+        self.compaction_policy = std::sync::Arc::new(policy);
+        self
+    }
+
+    pub fn open(&self, path: impl Into<std::path::PathBuf>) -> crate::utility::Result<KvStore> {
         let log_path = path.into();
         std::fs::create_dir_all(&log_path)?;
-        let files = crate::utility::sorted_log_files(&log_path)?;
-        let mut wasted_bytes = 0;
-        let mut total_bytes = 0;
-        let index = files
-            .iter()
-            .map(|file| crate::utility::parse_log_reader(file))
-            .collect::<crate::utility::Result<Vec<_>>>()?
-            .iter_mut()
-            .fold(std::collections::HashMap::new(), |mut acc, i| {
-                i.drain().for_each(|(k, (command, size))| {
-                    total_bytes += size;
-                    if let Some((_, old_size)) = acc.insert(k, (command, size)) {
-                        wasted_bytes += old_size;
-                    }
-                });
-                acc
-            });
-        let log_writer = crate::utility::new_log_file(&log_path)?;
-        Ok(KvStore {
-            index,
+        let lock_file = crate::utility::lock_store(&log_path)?;
+        crate::utility::remove_compaction_files(&log_path)?;
+        let mut manifest = match crate::manifest::Manifest::load(&log_path)? {
+            Some(manifest) => {
+                crate::utility::remove_unlisted_log_files(&log_path, &manifest.generations)?;
+                manifest
+            }
+            None => crate::manifest::Manifest::new(crate::utility::sorted_log_ids(&log_path)?),
+        };
+        let (index, mut space) = load_index(
+            &log_path,
+            &manifest.generations,
+            crate::utility::recover_log_reader,
+        )?;
+        let log_id = manifest.next_generation();
+        let log_writer = crate::utility::new_log_file(&log_path, log_id)?;
+        space.insert(log_id, SegmentSpace::default());
+        manifest.generations.push(log_id);
+        manifest.store(&log_path)?;
+        let syncer = match self.durability {
+            Durability::Interval(interval) => {
+                Some(Syncer::spawn(log_writer.get_ref().try_clone()?, interval))
+            }
+            Durability::Always | Durability::Never => None,
+        };
+        let shared = std::sync::Arc::new(Shared {
             log_path,
-            log_writer,
-            total_bytes,
-            wasted_bytes,
+            index: std::sync::RwLock::new(index),
+            safe_point: std::sync::atomic::AtomicU64::new(0),
+            manifest: std::sync::Mutex::new(manifest),
+            space: std::sync::Mutex::new(space),
+        });
+        Ok(KvStore {
+            shared: shared.clone(),
+            writer: Some(std::sync::Arc::new(std::sync::Mutex::new(LogWriter {
+                shared,
+                log_id,
+                log_writer,
+                compactor: None,
+                durability: self.durability,
+                max_segment_size: self.max_segment_size,
+                compaction_policy: self.compaction_policy.clone(),
+                syncer,
+                _lock_file: lock_file,
+            }))),
+            readers: std::sync::Mutex::new(std::collections::HashMap::new()),
         })
     }
+}
 
-    pub fn set(&mut self, key: String, value: std::string::String) -> crate::utility::Result<()> {
-        self.insert_command(
-            key.clone(),
-            crate::utility::WriteCommand::Set(key.clone(), value.clone()),
-        )?;
-        if self.waste_ratio() > 0.25f64 {
-            self.do_compaction()?;
+/// The points at which `KvStore::crash_during_compaction` can stop a compaction.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionStep {
+    WriteCompacted,
+    SyncCompacted,
+    RenameCompacted,
+    InstallCompacted,
+    RemoveStale,
+}
+
+struct Syncer {
+    file: std::sync::Arc<std::sync::Mutex<std::fs::File>>,
+    stop: std::sync::mpsc::Sender<()>,
+    handle: std::thread::JoinHandle<()>,
+}
+
+impl Syncer {
+    fn spawn(file: std::fs::File, interval: std::time::Duration) -> Syncer {
+        let file = std::sync::Arc::new(std::sync::Mutex::new(file));
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn({
+            let file = file.clone();
+            move || {
+                while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(interval)
+                {
+                    if let Err(err) = file.lock().unwrap().sync_data() {
+                        log::error!("Background log sync failed: {}", err);
+                    }
+                }
+            }
+        });
+        Syncer { file, stop, handle }
+    }
+
+    fn stop(self) {
+        drop(self.stop);
+        if self.handle.join().is_err() {
+            log::error!("Background log sync panicked");
+        }
+        if let Err(err) = self.file.lock().unwrap().sync_data() {
+            log::error!("Final log sync failed: {}", err);
         }
+    }
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<std::path::PathBuf>) -> crate::utility::Result<KvStore> {
+        KvStore::open_with_durability(path, Durability::default())
+    }
+
+    pub fn open_with_durability(
+        path: impl Into<std::path::PathBuf>,
+        durability: Durability,
+    ) -> crate::utility::Result<KvStore> {
+        KvStore::builder().durability(durability).open(path)
+    }
+
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::new()
+    }
+
+    /// Opens the store in `path` without taking the lock, so it can be read while
+    /// another process has it open for writing. The store only sees the log files
+    /// listed in the manifest when it was opened, never modifies the directory,
+    /// and fails every write with `KvsError::ReadOnly`.
+    pub fn open_read_only(path: impl Into<std::path::PathBuf>) -> crate::utility::Result<KvStore> {
+        let log_path = path.into();
+        let manifest = match crate::manifest::Manifest::load(&log_path)? {
+            Some(manifest) => manifest,
+            // Like `open`, treat a directory that does not exist yet as an empty store,
+            // just without creating it.
+            None if !log_path.exists() => crate::manifest::Manifest::new(Vec::new()),
+            None => crate::manifest::Manifest::new(crate::utility::sorted_log_ids(&log_path)?),
+        };
+        let (index, space) = load_index(
+            &log_path,
+            &manifest.generations,
+            crate::utility::skip_torn_log_reader,
+        )?;
+        Ok(KvStore {
+            shared: std::sync::Arc::new(Shared {
+                log_path,
+                index: std::sync::RwLock::new(index),
+                safe_point: std::sync::atomic::AtomicU64::new(0),
+                manifest: std::sync::Mutex::new(manifest),
+                space: std::sync::Mutex::new(space),
+            }),
+            writer: None,
+            readers: std::sync::Mutex::new(std::collections::HashMap::new()),
+        })
+    }
+
+    pub fn set(&self, key: String, value: std::string::String) -> crate::utility::Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<()> {
+        let mut writer = self.writer()?;
+        writer.insert_command(crate::utility::WriteCommand::Set(
+            key.to_vec(),
+            value.to_vec(),
+        ))?;
+        writer.maybe_compact()?;
+        Ok(())
+    }
+
+    /// Sets `key` to `value` until `ttl` has passed, after which the key reads as
+    /// absent and compaction drops it.
+    pub fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: std::time::Duration,
+    ) -> crate::utility::Result<()> {
+        self.set_bytes_with_ttl(key.as_bytes(), value.as_bytes(), ttl)
+    }
+
+    pub fn set_bytes_with_ttl(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        ttl: std::time::Duration,
+    ) -> crate::utility::Result<()> {
+        self.set_bytes_with_expiry(key, value, crate::utility::expiry_after(ttl))
+    }
+
+    /// Sets `key` to `value` until `expires_at`, in milliseconds since the Unix
+    /// epoch.
+    pub fn set_bytes_with_expiry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::utility::Result<()> {
+        let mut writer = self.writer()?;
+        writer.insert_command(crate::utility::WriteCommand::SetWithExpiry(
+            key.to_vec(),
+            value.to_vec(),
+            expires_at,
+        ))?;
+        writer.maybe_compact()?;
         Ok(())
     }
 
     pub fn get(&self, key: String) -> crate::utility::Result<Option<String>> {
-        if let Some((crate::utility::WriteCommand::Set(_, value), _)) = self.index.get(&key) {
-            println!("{}", value);
-            Ok(Some(String::from(value)))
+        self.get_bytes(key.as_bytes())?
+            .map(crate::utility::into_string)
+            .transpose()
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> crate::utility::Result<Option<Vec<u8>>> {
+        self.lookup(key)
+    }
+
+    pub fn remove(&self, key: String) -> crate::utility::Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&self, key: &[u8]) -> crate::utility::Result<()> {
+        let mut writer = self.writer()?;
+        if self.contains(key) {
+            writer.insert_command(crate::utility::WriteCommand::Remove(key.to_vec()))?;
+            writer.maybe_compact()
         } else {
-            println!("Key not found");
-            Ok(None)
+            Err(crate::utility::KvsError::KeyNotFound)
         }
     }
 
-    pub fn remove(&mut self, key: String) -> crate::utility::Result<()> {
-        if let Some((crate::utility::WriteCommand::Set(_, _), _)) = self.index.get(&key) {
-            self.insert_command(
-                key.clone(),
-                crate::utility::WriteCommand::Remove(key.clone()),
-            )?;
-            Ok(())
-        } else {
-            println!("Key not found");
-            Err(Box::new(crate::utility::KvsCommandError::KeyNotFound))
+    /// Replaces the value of `key` with `new` if its current value is `expected`,
+    /// where `None` stands for an absent key on either side. Returns whether the
+    /// write happened.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> crate::utility::Result<bool> {
+        self.compare_and_swap_bytes(
+            key.as_bytes(),
+            expected.as_ref().map(String::as_bytes),
+            new.as_ref().map(String::as_bytes),
+        )
+    }
+
+    pub fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current == expected, new)
+    }
+
+    /// Sets `key` to `value` unless it already exists. Returns whether the write
+    /// happened.
+    pub fn set_if_absent(&self, key: String, value: String) -> crate::utility::Result<bool> {
+        self.set_bytes_if_absent(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn set_bytes_if_absent(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current.is_none(), Some(value))
+    }
+
+    /// Sets `key` to `value` only if it already exists. Returns whether the write
+    /// happened.
+    pub fn set_if_present(&self, key: String, value: String) -> crate::utility::Result<bool> {
+        self.set_bytes_if_present(key.as_bytes(), value.as_bytes())
+    }
+
+    pub fn set_bytes_if_present(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current.is_some(), Some(value))
+    }
+
+    // Holding the writer lock keeps other writers out between the check and the write.
+    fn write_if(
+        &self,
+        key: &[u8],
+        condition: impl FnOnce(Option<&[u8]>) -> bool,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool> {
+        let mut writer = self.writer()?;
+        let current = self.lookup(key)?;
+        if !condition(current.as_deref()) {
+            return Ok(false);
+        }
+        match (new, current) {
+            (Some(value), _) => {
+                writer.insert_command(crate::utility::WriteCommand::Set(
+                    key.to_vec(),
+                    value.to_vec(),
+                ))?;
+                writer.maybe_compact()?;
+            }
+            (None, Some(_)) => {
+                writer.insert_command(crate::utility::WriteCommand::Remove(key.to_vec()))?;
+                writer.maybe_compact()?;
+            }
+            (None, None) => {}
+        }
+        Ok(true)
+    }
+
+    /// Applies every command in `batch`, or none of them if any remove targets a
+    /// missing key. The batch is written as one framed group, so it survives a
+    /// crash entirely or not at all. Returns the number of commands written, which
+    /// leaves out sets skipped through `WriteBatch::on_existing`.
+    pub fn write_batch(
+        &self,
+        mut batch: crate::batch::WriteBatch,
+    ) -> crate::utility::Result<usize> {
+        if batch.is_empty() {
+            return Ok(0);
         }
+        let mut writer = self.writer()?;
+        batch.check_existing(|key| Ok(self.contains(key)))?;
+        batch.check_removes(|key| Ok(self.contains(key)))?;
+        let written = batch.len();
+        if written > 0 {
+            writer.insert_batch(batch.into_commands())?;
+            writer.maybe_compact()?;
+        }
+        Ok(written)
+    }
+
+    /// Returns up to `limit` live keys in `range`, in lexicographic order, without
+    /// reading their values.
+    pub fn scan_keys(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+        limit: usize,
+    ) -> crate::utility::Result<Vec<Vec<u8>>> {
+        if crate::engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .shared
+            .index
+            .read()
+            .unwrap()
+            .range(range)
+            .filter(|(_, pointer)| pointer.kind.is_live())
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect())
+    }
+
+    /// Returns the live key/value pairs whose keys fall in `range`, in lexicographic
+    /// key order.
+    pub fn scan(
+        &self,
+        range: impl std::ops::RangeBounds<String>,
+    ) -> crate::utility::Result<crate::engine::Scan> {
+        crate::engine::into_string_scan(self.scan_bytes((
+            range.start_bound().cloned().map(String::into_bytes),
+            range.end_bound().cloned().map(String::into_bytes),
+        ))?)
+    }
+
+    pub fn scan_bytes(
+        &self,
+        range: impl std::ops::RangeBounds<Vec<u8>>,
+    ) -> crate::utility::Result<crate::engine::ByteScan> {
+        let mut pairs = Vec::new();
+        if crate::engine::is_empty_range(&range) {
+            return Ok(pairs.into_iter());
+        }
+        let index = self.shared.index.read().unwrap();
+        let mut readers = self.readers();
+        for (key, pointer) in index.range(range) {
+            if let Some(value) =
+                read_pointer(&mut readers, &self.shared.log_path, pointer)?.into_live_value()
+            {
+                pairs.push((key.clone(), value));
+            }
+        }
+        Ok(pairs.into_iter())
+    }
+
+    /// Returns the live key/value pairs whose keys start with `prefix`, in
+    /// lexicographic key order.
+    pub fn scan_prefix(&self, prefix: &str) -> crate::utility::Result<crate::engine::Scan> {
+        crate::engine::into_string_scan(self.scan_prefix_bytes(prefix.as_bytes())?)
+    }
+
+    pub fn scan_prefix_bytes(
+        &self,
+        prefix: &[u8],
+    ) -> crate::utility::Result<crate::engine::ByteScan> {
+        self.scan_bytes(crate::engine::prefix_range(prefix))
+    }
+
+    // The index stays locked while the record is read, so a background compaction
+    // cannot swap the pointer and delete its log file halfway through.
+    fn lookup(&self, key: &[u8]) -> crate::utility::Result<Option<Vec<u8>>> {
+        let index = self.shared.index.read().unwrap();
+        match index.get(key) {
+            Some(pointer) => Ok(self.read_command(pointer)?.into_live_value()),
+            None => Ok(None),
+        }
+    }
+
+    // Whether `key` holds a live value, from the index alone.
+    fn contains(&self, key: &[u8]) -> bool {
+        self.shared
+            .index
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|pointer| pointer.kind.is_live())
+    }
+
+    fn writer(&self) -> crate::utility::Result<std::sync::MutexGuard<'_, LogWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(crate::utility::KvsError::ReadOnly),
+        }
+    }
+
+    fn read_command(
+        &self,
+        pointer: &crate::utility::LogPointer,
+    ) -> crate::utility::Result<crate::utility::WriteCommand> {
+        read_pointer(&mut self.readers(), &self.shared.log_path, pointer)
+    }
+
+    fn readers(&self) -> std::sync::MutexGuard<'_, Readers> {
+        let mut readers = self.readers.lock().unwrap();
+        let safe_point = self
+            .shared
+            .safe_point
+            .load(std::sync::atomic::Ordering::SeqCst);
+        readers.retain(|log_id, _| *log_id >= safe_point);
+        readers
+    }
+
+    /// Counts the keys in the index and sums up the space taken by the log files.
+    pub fn stats(&self) -> crate::utility::Result<crate::engine::Stats> {
+        let (live_keys, tombstones) = {
+            let index = self.shared.index.read().unwrap();
+            let live_keys = index
+                .values()
+                .filter(|pointer| pointer.kind.is_live())
+                .count();
+            (live_keys, index.len() - live_keys)
+        };
+        let space = self.shared.space();
+        Ok(crate::engine::Stats {
+            live_keys,
+            tombstones,
+            total_bytes: space.values().map(|segment| segment.total_bytes).sum(),
+            wasted_bytes: space.values().map(|segment| segment.wasted_bytes).sum(),
+            log_files: space.len(),
+            last_compaction: self.shared.manifest.lock().unwrap().last_compaction,
+        })
+    }
+
+    /// Seals the active log file and compacts every log file into one, whatever the
+    /// compaction policy says. Waits for a background compaction to finish first,
+    /// and blocks writes until it is done.
+    pub fn compact(&self) -> crate::utility::Result<()> {
+        let mut writer = self.writer()?;
+        writer.finish_compaction();
+        let (sealed, compaction_id) = writer.seal_log_file()?;
+        compact(&self.shared, &sealed, compaction_id, None)
+    }
+
+    /// Seals the active log file, then runs a compaction over the sealed files that
+    /// stops right after `step`, as if the process died there, and drops the store.
+    /// Only meant for crash recovery tests.
+    #[doc(hidden)]
+    pub fn crash_during_compaction(self, step: CompactionStep) -> crate::utility::Result<()> {
+        let mut writer = self.writer()?;
+        writer.finish_compaction();
+        let (sealed, compaction_id) = writer.seal_log_file()?;
+        compact(&self.shared, &sealed, compaction_id, Some(step))
+    }
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> KvStore {
+        KvStore {
+            shared: self.shared.clone(),
+            writer: self.writer.clone(),
+            readers: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
+    }
+}
+
+impl crate::engine::KvsEngine for KvStore {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<()> {
+        KvStore::set_bytes(self, key, value)
+    }
+
+    fn set_bytes_with_expiry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::utility::Result<()> {
+        KvStore::set_bytes_with_expiry(self, key, value, expires_at)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> crate::utility::Result<Option<Vec<u8>>> {
+        KvStore::get_bytes(self, key)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> crate::utility::Result<()> {
+        KvStore::remove_bytes(self, key)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool> {
+        KvStore::compare_and_swap_bytes(self, key, expected, new)
+    }
+
+    fn set_bytes_if_absent(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        KvStore::set_bytes_if_absent(self, key, value)
+    }
+
+    fn set_bytes_if_present(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        KvStore::set_bytes_if_present(self, key, value)
+    }
+
+    fn write_batch(&self, batch: crate::batch::WriteBatch) -> crate::utility::Result<usize> {
+        KvStore::write_batch(self, batch)
+    }
+
+    fn scan_bytes(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+    ) -> crate::utility::Result<crate::engine::ByteScan> {
+        KvStore::scan_bytes(self, (start, end))
+    }
+
+    fn scan_keys(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+        limit: usize,
+    ) -> crate::utility::Result<Vec<Vec<u8>>> {
+        KvStore::scan_keys(self, (start, end), limit)
+    }
+
+    fn stats(&self) -> crate::utility::Result<crate::engine::Stats> {
+        KvStore::stats(self)
+    }
+
+    fn compact(&self) -> crate::utility::Result<()> {
+        KvStore::compact(self)
     }
+}
 
-    fn waste_ratio(&self) -> f64 {
-        self.wasted_bytes as f64 / self.total_bytes as f64
+impl LogWriter {
+    fn space_usage(&self) -> crate::compaction::SpaceUsage {
+        let space = self.shared.space();
+        crate::compaction::SpaceUsage {
+            total_bytes: space.values().map(|segment| segment.total_bytes).sum(),
+            wasted_bytes: space.values().map(|segment| segment.wasted_bytes).sum(),
+            // Every log file but the active one.
+            sealed_segments: space.len().saturating_sub(1),
+        }
+    }
+
+    fn maybe_compact(&mut self) -> crate::utility::Result<()> {
+        if self.compaction_policy.should_compact(&self.space_usage()) {
+            self.do_compaction()?;
+        }
+        Ok(())
     }
 
     fn do_compaction(&mut self) -> crate::utility::Result<()> {
-        self.clear_log_file()?;
-        self.dump_log_file()?;
+        if let Some(compactor) = &self.compactor {
+            if !compactor.is_finished() {
+                return Ok(());
+            }
+        }
+        self.finish_compaction();
+        let (sealed, compaction_id) = self.seal_log_file()?;
+        let shared = self.shared.clone();
+        self.compactor = Some(std::thread::spawn(move || {
+            if let Err(err) = compact(&shared, &sealed, compaction_id, None) {
+                log::error!("Background compaction failed: {}", err);
+            }
+        }));
         Ok(())
     }
 
-    fn clear_log_file(&mut self) -> crate::utility::Result<()> {
-        for f in crate::utility::log_files(&self.log_path)? {
-            std::fs::remove_file(f)?;
+    fn finish_compaction(&mut self) {
+        if let Some(compactor) = self.compactor.take() {
+            if compactor.join().is_err() {
+                log::error!("Background compaction panicked");
+            }
+        }
+    }
+
+    // Moves writes over to a fresh log file, leaving a gap in the ids right after the
+    // old one for the compacted generation. Returns the ids of every log file that is
+    // now immutable, together with the id reserved for compacting them.
+    fn seal_log_file(&mut self) -> crate::utility::Result<(Vec<u64>, u64)> {
+        let sealed = self.shared.manifest.lock().unwrap().generations.clone();
+        let compaction_id = self.log_id + 1;
+        self.switch_log_file(compaction_id + 1)?;
+        Ok((sealed, compaction_id))
+    }
+
+    // Starts a new segment once the active log file has outgrown `max_segment_size`.
+    // A running compaction always writes to an id below the active one, so the next
+    // id is free.
+    fn roll_log_file(&mut self) -> crate::utility::Result<()> {
+        if self.log_writer.pos() >= self.max_segment_size {
+            self.switch_log_file(self.log_id + 1)?;
         }
         Ok(())
     }
 
-    fn dump_log_file(&mut self) -> crate::utility::Result<()> {
-        let mut writer = crate::utility::new_log_file(&self.log_path)?;
-        for (command, _) in self.index.values() {
-            crate::utility::write_command(&mut writer, command)?;
+    // The old log file is synced first, since only the newest generation may end in
+    // a torn write.
+    fn switch_log_file(&mut self, log_id: u64) -> crate::utility::Result<()> {
+        self.log_writer.get_ref().sync_data()?;
+        let log_writer = crate::utility::new_log_file(&self.shared.log_path, log_id)?;
+        self.shared
+            .space
+            .lock()
+            .unwrap()
+            .insert(log_id, SegmentSpace::default());
+        {
+            let mut manifest = self.shared.manifest.lock().unwrap();
+            manifest.generations.push(log_id);
+            manifest.store(&self.shared.log_path)?;
+        }
+        if let Some(syncer) = &self.syncer {
+            *syncer.file.lock().unwrap() = log_writer.get_ref().try_clone()?;
         }
-        self.log_writer = writer;
+        self.log_id = log_id;
+        self.log_writer = log_writer;
         Ok(())
     }
 
     fn insert_command(
         &mut self,
-        key: String,
         command: crate::utility::WriteCommand,
     ) -> crate::utility::Result<()> {
-        let size = crate::utility::write_command(&mut self.log_writer, &command)?;
-        if let Some((_, waste_bytes)) = self.index.insert(key, (command, size)) {
-            self.update_wasted_bytes(waste_bytes);
+        let offset = self.log_writer.pos();
+        let len = crate::utility::write_command(&mut self.log_writer, &command)?;
+        if self.durability == Durability::Always {
+            self.log_writer.get_ref().sync_data()?;
+        }
+        let pointer = crate::utility::LogPointer {
+            log_id: self.log_id,
+            offset,
+            len,
+            kind: command.kind(),
+        };
+        let old_pointer = self
+            .shared
+            .index
+            .write()
+            .unwrap()
+            .insert(command.into_key(), pointer);
+        self.update_space(len, [pointer], old_pointer);
+        self.roll_log_file()
+    }
+
+    fn insert_batch(
+        &mut self,
+        commands: Vec<crate::utility::WriteCommand>,
+    ) -> crate::utility::Result<()> {
+        let start = self.log_writer.pos();
+        let records = crate::utility::write_batch(&mut self.log_writer, &commands)?;
+        if self.durability == Durability::Always {
+            self.log_writer.get_ref().sync_data()?;
+        }
+        let written = self.log_writer.pos() - start;
+        let mut inserted = Vec::with_capacity(commands.len());
+        let mut overwritten = Vec::new();
+        {
+            let mut index = self.shared.index.write().unwrap();
+            for (command, (offset, len)) in commands.into_iter().zip(records) {
+                let pointer = crate::utility::LogPointer {
+                    log_id: self.log_id,
+                    offset,
+                    len,
+                    kind: command.kind(),
+                };
+                let key = command.into_key();
+                inserted.push(pointer);
+                overwritten.extend(index.insert(key, pointer));
+            }
+        }
+        self.update_space(written, inserted, overwritten);
+        self.roll_log_file()
+    }
+
+    // Adds `written` bytes holding the `inserted` records to the active log file and
+    // marks the records the write `overwritten` as garbage. Records in log files that
+    // a compaction has already replaced are left alone, since the compacted file
+    // accounts for them.
+    fn update_space(
+        &self,
+        written: u64,
+        inserted: impl IntoIterator<Item = crate::utility::LogPointer>,
+        overwritten: impl IntoIterator<Item = crate::utility::LogPointer>,
+    ) {
+        let mut space = self.shared.space.lock().unwrap();
+        let segment = space.entry(self.log_id).or_default();
+        segment.total_bytes += written;
+        for pointer in inserted {
+            segment.insert(&pointer);
+        }
+        for pointer in overwritten {
+            if let Some(segment) = space.get_mut(&pointer.log_id) {
+                segment.waste(&pointer);
+            }
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        self.finish_compaction();
+        if let Some(syncer) = self.syncer.take() {
+            syncer.stop();
         }
-        Ok(())
     }
+}
 
-    fn update_wasted_bytes(&mut self, command_size: u64) {
-        self.wasted_bytes += command_size;
+// Replays the `generations` from oldest to newest, from their hint files where
+// compaction left one, and reading the newest one with `read_newest` since it may
+// end in a torn write. Returns the index along with the size of every log file and
+// how much of it is taken up by records that were overwritten or never indexed.
+fn load_index(
+    log_path: &std::path::Path,
+    generations: &[u64],
+    read_newest: fn(&std::path::PathBuf) -> crate::utility::Result<FileIndex>,
+) -> crate::utility::Result<(Index, Space)> {
+    let mut index = Index::new();
+    let mut space = Space::new();
+    for (idx, log_id) in generations.iter().enumerate() {
+        let file = crate::utility::log_path(log_path, *log_id);
+        let file_index = if idx + 1 == generations.len() {
+            read_newest(&file)?
+        } else {
+            match crate::utility::read_hint_file(log_path, *log_id)? {
+                Some(file_index) => file_index,
+                None => crate::utility::parse_log_reader(&file)?,
+            }
+        };
+        let total_bytes = std::fs::metadata(&file)?.len();
+        // Tombstones and records that have expired already are garbage from the start.
+        let live = || file_index.values().filter(|pointer| pointer.kind.is_live());
+        let live_bytes = live().map(|pointer| pointer.len).sum::<u64>();
+        let mut segment = SegmentSpace {
+            total_bytes,
+            wasted_bytes: total_bytes.saturating_sub(live_bytes),
+            ..SegmentSpace::default()
+        };
+        for pointer in live() {
+            segment.insert(pointer);
+        }
+        space.insert(*log_id, segment);
+        for (key, pointer) in file_index {
+            if let Some(old_pointer) = index.insert(key, pointer) {
+                if let Some(segment) = space.get_mut(&old_pointer.log_id) {
+                    segment.waste(&old_pointer);
+                }
+            }
+        }
+    }
+    Ok((index, space))
+}
+
+// Rewrites the live entries of the `sealed` log files into `compaction_id`, then
+// points the index at the new file and deletes the sealed ones. The compacted file
+// is written under a temporary name and only renamed into place and swapped into
+// the manifest for the sealed files once it is durable, so a crash at any point
+// leaves the manifest listing either the old set of log files or a compacted file
+// that already holds every entry it replaces. Entries overwritten while compaction
+// runs keep their newer pointer. Entries that have expired are left out, and so are
+// tombstones: the sealed files are every generation older than the active one, so
+// no older record is left for a tombstone to hide.
+fn compact(
+    shared: &Shared,
+    sealed: &[u64],
+    compaction_id: u64,
+    crash_after: Option<CompactionStep>,
+) -> crate::utility::Result<()> {
+    let log_path = &shared.log_path;
+    let live = shared
+        .index
+        .read()
+        .unwrap()
+        .iter()
+        .filter(|(_, pointer)| sealed.contains(&pointer.log_id))
+        .map(|(key, pointer)| (key.clone(), *pointer))
+        .collect::<Vec<_>>();
+    let mut readers = std::collections::HashMap::new();
+    let mut writer = crate::utility::new_compaction_file(log_path, compaction_id)?;
+    let mut swaps = Vec::with_capacity(live.len());
+    let mut hints = Vec::with_capacity(live.len());
+    let mut dropped = Vec::new();
+    for (key, pointer) in live {
+        if !pointer.kind.is_live() {
+            dropped.push((key, pointer));
+            continue;
+        }
+        let command = read_pointer(&mut readers, log_path, &pointer)?;
+        let offset = writer.pos();
+        let len = crate::utility::write_command(&mut writer, &command)?;
+        hints.push(crate::utility::HintEntry {
+            key: key.clone(),
+            kind: pointer.kind,
+            offset,
+            len,
+        });
+        let compacted = crate::utility::LogPointer {
+            log_id: compaction_id,
+            offset,
+            len,
+            kind: pointer.kind,
+        };
+        swaps.push((key, pointer, compacted));
     }
+    drop(readers);
+    let compacted_bytes = writer.pos();
+    if crash_after == Some(CompactionStep::WriteCompacted) {
+        return Ok(());
+    }
+    writer.get_ref().sync_all()?;
+    if crash_after == Some(CompactionStep::SyncCompacted) {
+        return Ok(());
+    }
+    crate::utility::write_hint_file(log_path, compaction_id, &hints)?;
+    std::fs::rename(
+        crate::utility::compaction_path(log_path, compaction_id),
+        crate::utility::log_path(log_path, compaction_id),
+    )?;
+    crate::utility::sync_dir(log_path)?;
+    if crash_after == Some(CompactionStep::RenameCompacted) {
+        return Ok(());
+    }
+    {
+        let mut manifest = shared.manifest.lock().unwrap();
+        manifest
+            .generations
+            .retain(|log_id| !sealed.contains(log_id));
+        manifest.generations.push(compaction_id);
+        manifest.generations.sort_unstable();
+        manifest.last_compaction = Some(crate::utility::unix_millis());
+        manifest.store(log_path)?;
+    }
+    if crash_after == Some(CompactionStep::InstallCompacted) {
+        return Ok(());
+    }
+    {
+        let mut index = shared.index.write().unwrap();
+        let mut segment = SegmentSpace {
+            total_bytes: compacted_bytes,
+            ..SegmentSpace::default()
+        };
+        for (key, pointer, compacted) in swaps {
+            match index.get_mut(&key) {
+                Some(current) if *current == pointer => {
+                    *current = compacted;
+                    segment.insert(&compacted);
+                }
+                _ => segment.wasted_bytes += compacted.len,
+            }
+        }
+        for (key, pointer) in dropped {
+            if index.get(&key) == Some(&pointer) {
+                index.remove(&key);
+            }
+        }
+        let mut space = shared.space.lock().unwrap();
+        space.retain(|log_id, _| !sealed.contains(log_id));
+        space.insert(compaction_id, segment);
+    }
+    shared
+        .safe_point
+        .store(compaction_id, std::sync::atomic::Ordering::SeqCst);
+    for log_id in sealed {
+        std::fs::remove_file(crate::utility::log_path(log_path, *log_id))?;
+        let hint_path = crate::utility::hint_path(log_path, *log_id);
+        if hint_path.exists() {
+            std::fs::remove_file(hint_path)?;
+        }
+        if crash_after == Some(CompactionStep::RemoveStale) {
+            return Ok(());
+        }
+    }
+    crate::utility::sync_dir(log_path)?;
+    Ok(())
+}
+
+fn read_pointer(
+    readers: &mut Readers,
+    log_path: &std::path::Path,
+    pointer: &crate::utility::LogPointer,
+) -> crate::utility::Result<crate::utility::WriteCommand> {
+    let reader = match readers.entry(pointer.log_id) {
+        std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            entry.insert(crate::utility::open_log_reader(log_path, pointer.log_id)?)
+        }
+    };
+    crate::utility::read_command(
+        reader,
+        &crate::utility::log_path(log_path, pointer.log_id),
+        pointer,
+    )
 }
@@ -1,2 +1,12 @@
+pub mod batch;
+pub mod check;
+pub mod client;
+pub mod compaction;
+pub mod dump;
+pub mod engine;
 pub mod kvs;
+pub mod manifest;
+pub mod memory;
+pub mod protocol;
+pub mod server;
 pub mod utility;
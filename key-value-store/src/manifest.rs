@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+/// The name of the file in a store directory that lists its live log files.
+pub const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The version of the on-disk format written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The engine type recorded in manifests written by `KvStore`.
+pub const ENGINE: &str = "kvs";
+
+/// Records which log file generations make up a store, oldest first, together with
+/// the format version and the engine that wrote them.
+///
+/// `KvStore::open` replays exactly the generations listed here, so log files that
+/// are not listed, e.g. left behind by a crash halfway through a compaction, are
+/// never mistaken for live data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub engine: String,
+    pub generations: Vec<u64>,
+    /// When a compaction last finished, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_compaction: Option<u64>,
+}
+
+impl Manifest {
+    pub fn new(generations: Vec<u64>) -> Manifest {
+        Manifest {
+            format_version: FORMAT_VERSION,
+            engine: ENGINE.to_owned(),
+            generations,
+            last_compaction: None,
+        }
+    }
+
+    /// Reads the manifest of the store in `path`, or returns `None` if the store has
+    /// none yet. Fails if the store was written by another engine or format version.
+    pub fn load(path: &std::path::Path) -> crate::utility::Result<Option<Manifest>> {
+        let contents = match std::fs::read(path.join(MANIFEST_FILE)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let manifest: Manifest = serde_json::from_slice(&contents)?;
+        if manifest.engine != ENGINE {
+            return Err(crate::utility::KvsError::IncompatibleStore(format!(
+                "written by engine {}",
+                manifest.engine
+            )));
+        }
+        if manifest.format_version != FORMAT_VERSION {
+            return Err(crate::utility::KvsError::IncompatibleStore(format!(
+                "format version {} is not supported",
+                manifest.format_version
+            )));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Replaces the manifest of the store in `path`. The new manifest is written and
+    /// synced under a temporary name first and then renamed over the old one, so a
+    /// crash leaves either the old or the new manifest in place.
+    pub fn store(&self, path: &std::path::Path) -> crate::utility::Result<()> {
+        let temp_path = path.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = std::fs::File::create(&temp_path)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path.join(MANIFEST_FILE))?;
+        crate::utility::sync_dir(path)
+    }
+
+    /// The generation for the next log file, which sorts after every live one.
+    pub fn next_generation(&self) -> u64 {
+        self.generations.iter().max().map_or(1, |last| last + 1)
+    }
+}
@@ -0,0 +1,253 @@
+/// A `KvsEngine` that keeps everything in a `BTreeMap` and forgets it once the last
+/// clone is dropped.
+#[derive(Debug, Default, Clone)]
+pub struct MemStore {
+    entries: std::sync::Arc<std::sync::RwLock<Entries>>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: std::collections::BTreeMap<Vec<u8>, Entry>,
+    // The keys of entries with an expiry time, in the order they expire, so every
+    // write can drop the expired ones without walking the whole map.
+    expiring: std::collections::BTreeSet<(u64, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn live_value(&self) -> Option<&[u8]> {
+        match self.expires_at {
+            Some(expires_at) if crate::utility::is_expired(expires_at) => None,
+            _ => Some(&self.value),
+        }
+    }
+}
+
+impl Entries {
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key).and_then(Entry::live_value)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        self.remove(&key);
+        if let Some(expires_at) = entry.expires_at {
+            self.expiring.insert((expires_at, key.clone()));
+        }
+        self.map.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        if let Some(expires_at) = entry.expires_at {
+            self.expiring.remove(&(expires_at, key.to_vec()));
+        }
+        Some(entry)
+    }
+
+    fn remove_expired(&mut self) {
+        while let Some((expires_at, _)) = self.expiring.first() {
+            if !crate::utility::is_expired(*expires_at) {
+                break;
+            }
+            if let Some((_, key)) = self.expiring.pop_first() {
+                self.map.remove(&key);
+            }
+        }
+    }
+}
+
+impl MemStore {
+    pub fn new() -> MemStore {
+        MemStore::default()
+    }
+
+    // Locks the entries for writing, dropping the expired ones first.
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Entries> {
+        let mut entries = self.entries.write().unwrap();
+        entries.remove_expired();
+        entries
+    }
+
+    fn write_if(
+        &self,
+        key: &[u8],
+        condition: impl FnOnce(Option<&[u8]>) -> bool,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool> {
+        let mut entries = self.write();
+        if !condition(entries.get(key)) {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => {
+                entries.insert(
+                    key.to_vec(),
+                    Entry {
+                        value: value.to_vec(),
+                        expires_at: None,
+                    },
+                );
+            }
+            None => {
+                entries.remove(key);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl crate::engine::KvsEngine for MemStore {
+    fn set_bytes(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<()> {
+        self.write().insert(
+            key.to_vec(),
+            Entry {
+                value: value.to_vec(),
+                expires_at: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn set_bytes_with_expiry(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) -> crate::utility::Result<()> {
+        self.write().insert(
+            key.to_vec(),
+            Entry {
+                value: value.to_vec(),
+                expires_at: Some(expires_at),
+            },
+        );
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> crate::utility::Result<Option<Vec<u8>>> {
+        Ok(self.entries.read().unwrap().get(key).map(<[u8]>::to_vec))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> crate::utility::Result<()> {
+        self.write()
+            .remove(key)
+            .filter(|entry| entry.live_value().is_some())
+            .map(|_| ())
+            .ok_or(crate::utility::KvsError::KeyNotFound)
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current == expected, new)
+    }
+
+    fn set_bytes_if_absent(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current.is_none(), Some(value))
+    }
+
+    fn set_bytes_if_present(&self, key: &[u8], value: &[u8]) -> crate::utility::Result<bool> {
+        self.write_if(key, |current| current.is_some(), Some(value))
+    }
+
+    fn write_batch(&self, mut batch: crate::batch::WriteBatch) -> crate::utility::Result<usize> {
+        let mut entries = self.write();
+        batch.check_existing(|key| Ok(entries.get(key).is_some()))?;
+        batch.check_removes(|key| Ok(entries.get(key).is_some()))?;
+        let written = batch.len();
+        for command in batch.into_commands() {
+            match command {
+                crate::utility::WriteCommand::Set(key, value) => {
+                    entries.insert(
+                        key,
+                        Entry {
+                            value,
+                            expires_at: None,
+                        },
+                    );
+                }
+                crate::utility::WriteCommand::SetWithExpiry(key, value, expires_at) => {
+                    entries.insert(
+                        key,
+                        Entry {
+                            value,
+                            expires_at: Some(expires_at),
+                        },
+                    );
+                }
+                crate::utility::WriteCommand::Remove(key) => {
+                    entries.remove(&key);
+                }
+            }
+        }
+        Ok(written)
+    }
+
+    fn scan_bytes(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+    ) -> crate::utility::Result<crate::engine::ByteScan> {
+        let range = (start, end);
+        if crate::engine::is_empty_range(&range) {
+            return Ok(Vec::new().into_iter());
+        }
+        Ok(self
+            .entries
+            .read()
+            .unwrap()
+            .map
+            .range(range)
+            .filter_map(|(key, entry)| Some((key.clone(), entry.live_value()?.to_vec())))
+            .collect::<Vec<_>>()
+            .into_iter())
+    }
+
+    fn scan_keys(
+        &self,
+        start: std::ops::Bound<Vec<u8>>,
+        end: std::ops::Bound<Vec<u8>>,
+        limit: usize,
+    ) -> crate::utility::Result<Vec<Vec<u8>>> {
+        let range = (start, end);
+        if crate::engine::is_empty_range(&range) {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .entries
+            .read()
+            .unwrap()
+            .map
+            .range(range)
+            .filter(|(_, entry)| entry.live_value().is_some())
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect())
+    }
+
+    fn stats(&self) -> crate::utility::Result<crate::engine::Stats> {
+        let map = &self.entries.read().unwrap().map;
+        let live_keys = map
+            .values()
+            .filter(|entry| entry.live_value().is_some())
+            .count();
+        Ok(crate::engine::Stats {
+            live_keys,
+            tombstones: map.len() - live_keys,
+            ..crate::engine::Stats::default()
+        })
+    }
+
+    fn compact(&self) -> crate::utility::Result<()> {
+        self.entries.write().unwrap().remove_expired();
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+//! The request/response protocol spoken between `kvs-client` and `kvs-server`.
+//!
+//! Both sides exchange JSON documents written back to back on one TCP connection.
+//! The client writes a `Request`, the server answers it with exactly one `Response`,
+//! and the client may then send the next request on the same connection. Writes
+//! reuse the `WriteCommand` log types, and keys and values travel as arrays of
+//! bytes, so a session setting `k` to `v` looks like
+//!
+//! ```text
+//! {"Get":[107]}                   -> {"Value":[118]} or {"Value":null}
+//! {"Write":{"Set":[[107],[118]]}} -> "Done"
+//! {"Write":{"Remove":[107]}}      -> "Done" or "KeyNotFound"
+//! ```
+//!
+//! An expiring set carries its absolute expiry time in Unix milliseconds, e.g.
+//! `{"Write":{"SetWithExpiry":[[107],[118],1700000000000]}}`.
+//!
+//! Any other failure on the server is answered with `{"Error":"<message>"}`.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get(Vec<u8>),
+    Write(crate::utility::WriteCommand),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Value(Option<Vec<u8>>),
+    Done,
+    KeyNotFound,
+    Error(String),
+}
+
+pub const DEFAULT_ADDR: &str = "127.0.0.1:4000";
@@ -0,0 +1,92 @@
+use std::io::Write;
+
+/// Serves one `KvsEngine` to `kvs-client`s over TCP, with a thread per connection.
+pub struct KvsServer {
+    engine: std::sync::Arc<dyn crate::engine::KvsEngine>,
+}
+
+impl KvsServer {
+    pub fn new(engine: std::sync::Arc<dyn crate::engine::KvsEngine>) -> KvsServer {
+        KvsServer { engine }
+    }
+
+    pub fn run(&self, addr: impl std::net::ToSocketAddrs) -> crate::utility::Result<()> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let engine = self.engine.clone();
+                    std::thread::spawn(move || {
+                        if let Err(err) = serve(engine.as_ref(), stream) {
+                            log::error!("Error serving client: {}", err);
+                        }
+                    });
+                }
+                Err(err) => log::error!("Connection failed: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn serve(
+    engine: &dyn crate::engine::KvsEngine,
+    stream: std::net::TcpStream,
+) -> crate::utility::Result<()> {
+    let peer_addr = stream.peer_addr()?;
+    let reader = std::io::BufReader::new(&stream);
+    let mut writer = std::io::BufWriter::new(&stream);
+    let requests =
+        serde_json::Deserializer::from_reader(reader).into_iter::<crate::protocol::Request>();
+    for request in requests {
+        let request = match request {
+            Ok(request) => request,
+            // A malformed request leaves no way to find the start of the next one, so
+            // answer it and hang up.
+            Err(err) => {
+                if !err.is_io() {
+                    let response = crate::protocol::Response::Error(err.to_string());
+                    serde_json::to_writer(&mut writer, &response)?;
+                    writer.flush()?;
+                }
+                return Err(err.into());
+            }
+        };
+        log::debug!("Request from {}: {:?}", peer_addr, request);
+        let response = match handle(engine, request) {
+            Ok(response) => response,
+            Err(crate::utility::KvsError::KeyNotFound) => crate::protocol::Response::KeyNotFound,
+            Err(err) => crate::protocol::Response::Error(err.to_string()),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn handle(
+    engine: &dyn crate::engine::KvsEngine,
+    request: crate::protocol::Request,
+) -> crate::utility::Result<crate::protocol::Response> {
+    match request {
+        crate::protocol::Request::Get(key) => {
+            Ok(crate::protocol::Response::Value(engine.get_bytes(&key)?))
+        }
+        crate::protocol::Request::Write(crate::utility::WriteCommand::Set(key, value)) => {
+            engine.set_bytes(&key, &value)?;
+            Ok(crate::protocol::Response::Done)
+        }
+        crate::protocol::Request::Write(crate::utility::WriteCommand::SetWithExpiry(
+            key,
+            value,
+            expires_at,
+        )) => {
+            engine.set_bytes_with_expiry(&key, &value, expires_at)?;
+            Ok(crate::protocol::Response::Done)
+        }
+        crate::protocol::Request::Write(crate::utility::WriteCommand::Remove(key)) => {
+            engine.remove_bytes(&key)?;
+            Ok(crate::protocol::Response::Done)
+        }
+    }
+}
@@ -1,31 +1,353 @@
 use serde::{Deserialize, Serialize};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 
-pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+struct PendingBatch {
+    count: u32,
+    offset: u64,
+    entries: Vec<(Vec<u8>, LogPointer)>,
+}
 
 pub fn parse_log_reader(
     path: &std::path::PathBuf,
-) -> Result<std::collections::HashMap<String, (WriteCommand, u64)>> {
+) -> Result<std::collections::HashMap<Vec<u8>, LogPointer>> {
+    parse_log_until(path, None)
+}
+
+// Records written between a batch begin and its commit marker only become visible
+// once the commit is read. A batch still open at the end of the file, or at `end`,
+// was cut short by a crash and is skipped entirely.
+fn parse_log_until(
+    path: &std::path::PathBuf,
+    end: Option<u64>,
+) -> Result<std::collections::HashMap<Vec<u8>, LogPointer>> {
+    let log_id = log_id(path)?;
     let mut reader = BufReaderWithPos::new(std::fs::OpenOptions::new().read(true).open(path)?)?;
     let mut index = std::collections::HashMap::new();
+    let mut batch: Option<PendingBatch> = None;
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = serde_json::Deserializer::from_reader(&mut reader).into_iter::<WriteCommand>();
-    while let Some(command) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        let offset_pos = new_pos - pos;
-        match command? {
-            WriteCommand::Set(key, value) => {
-                index.insert(key.clone(), (WriteCommand::Set(key, value), offset_pos));
+    while end.is_none_or(|end| pos < end) {
+        let record = match read_record(&mut reader, path)? {
+            Some(record) => record,
+            None => break,
+        };
+        let new_pos = reader.pos();
+        let corrupted = || KvsError::CorruptedLog {
+            path: path.to_path_buf(),
+            offset: pos,
+        };
+        match record {
+            Record::Command(command) => {
+                let pointer = LogPointer {
+                    log_id,
+                    offset: pos,
+                    len: new_pos - pos,
+                    kind: command.kind(),
+                };
+                let key = command.into_key();
+                match &mut batch {
+                    Some(pending) => pending.entries.push((key, pointer)),
+                    None => {
+                        index.insert(key, pointer);
+                    }
+                }
             }
-            WriteCommand::Remove(key) => {
-                index.insert(key.clone(), (WriteCommand::Remove(key), offset_pos));
+            Record::BatchBegin(count) => {
+                if batch.is_some() {
+                    return Err(corrupted());
+                }
+                batch = Some(PendingBatch {
+                    count,
+                    offset: pos,
+                    entries: Vec::with_capacity(count as usize),
+                });
             }
+            Record::BatchCommit(count) => match batch.take() {
+                Some(pending)
+                    if pending.count == count && pending.entries.len() == count as usize =>
+                {
+                    index.extend(pending.entries);
+                }
+                _ => return Err(corrupted()),
+            },
         };
         pos = new_pos;
     }
+    if let Some(pending) = batch {
+        log::warn!(
+            "Skipping incomplete write batch in {} at offset {}",
+            path.display(),
+            pending.offset
+        );
+    }
     Ok(index)
 }
 
+/// Parses `path` like `parse_log_reader`, but if the last record in the file was
+/// only partially written, cuts the file back to the end of the last complete
+/// record instead of failing. Only meant for the newest log file, where a torn
+/// write is the expected result of a crash. A record that runs past the end of the
+/// file but is followed by readable ones is reported as `KvsError::CorruptedLog`.
+pub fn recover_log_reader(
+    path: &std::path::PathBuf,
+) -> Result<std::collections::HashMap<Vec<u8>, LogPointer>> {
+    match parse_log_reader(path) {
+        Err(err) => match err {
+            KvsError::TruncatedLog { offset, .. } => {
+                check_torn_tail(path, offset)?;
+                log::warn!(
+                    "Truncating torn write at the end of {} at offset {}",
+                    path.display(),
+                    offset
+                );
+                let file = std::fs::OpenOptions::new().write(true).open(path)?;
+                file.set_len(offset)?;
+                file.sync_all()?;
+                parse_log_reader(path)
+            }
+            _ => Err(err),
+        },
+        index => index,
+    }
+}
+
+/// Parses `path` like `recover_log_reader`, but leaves a torn write at the end of
+/// the file in place and only skips it, for stores opened read-only.
+pub fn skip_torn_log_reader(
+    path: &std::path::PathBuf,
+) -> Result<std::collections::HashMap<Vec<u8>, LogPointer>> {
+    match parse_log_reader(path) {
+        Err(KvsError::TruncatedLog { offset, .. }) => {
+            check_torn_tail(path, offset)?;
+            parse_log_until(path, Some(offset))
+        }
+        index => index,
+    }
+}
+
+// A crash only ever tears the last record in a file. If a readable record follows
+// the one at `offset`, its length field was damaged instead.
+fn check_torn_tail(path: &std::path::Path, offset: u64) -> Result<()> {
+    let bytes = std::fs::read(path)?;
+    match next_record(&bytes, offset + 1, path) {
+        Some(_) => Err(KvsError::CorruptedLog {
+            path: path.to_path_buf(),
+            offset,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The offset of the first record in `bytes` at or after `from` that decodes.
+pub fn next_record(bytes: &[u8], from: u64, path: &std::path::Path) -> Option<u64> {
+    (from as usize..bytes.len())
+        .filter(|offset| bytes[*offset..].starts_with(&RECORD_MAGIC))
+        .find(|offset| {
+            let mut reader = match BufReaderWithPos::new(io::Cursor::new(&bytes[*offset..])) {
+                Ok(reader) => reader,
+                Err(_) => return false,
+            };
+            matches!(read_record(&mut reader, path), Ok(Some(_)))
+        })
+        .map(|offset| offset as u64)
+}
+
+/// The name of the file in a store directory that a writing `KvStore` holds an
+/// exclusive lock on. It contains the pid of the process holding the lock.
+pub const LOCK_FILE: &str = "kvs.lock";
+
+/// Takes the exclusive lock on the store in `path`, or fails with `StoreLocked` if
+/// another process, or another `KvStore` in this one, already holds it. The lock is
+/// released when the returned file is closed.
+pub fn lock_store(path: &std::path::Path) -> Result<std::fs::File> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+    if let Err(err) = fs2::FileExt::try_lock_exclusive(&file) {
+        if err.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+            return Err(err.into());
+        }
+        let mut pid = String::new();
+        file.read_to_string(&mut pid)?;
+        return Err(KvsError::StoreLocked {
+            path: path.to_path_buf(),
+            pid: pid.trim().parse().ok(),
+        });
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(file)
+}
+
+pub fn read_command(
+    reader: &mut BufReaderWithPos<std::fs::File>,
+    path: &std::path::Path,
+    pointer: &LogPointer,
+) -> Result<WriteCommand> {
+    reader.seek(SeekFrom::Start(pointer.offset))?;
+    match read_record(reader, path)? {
+        Some(Record::Command(command)) if reader.pos() == pointer.offset + pointer.len => {
+            Ok(command)
+        }
+        _ => Err(KvsError::CorruptedLog {
+            path: path.to_path_buf(),
+            offset: pointer.offset,
+        }),
+    }
+}
+
+/// Reads the record starting at the current position of `reader`.
+///
+/// Returns `Ok(None)` when the reader is exactly at the end of the file. A record
+/// that is cut short by the end of the file is reported as
+/// `KvsError::TruncatedLog`, and one with a bad header or checksum as
+/// `KvsError::CorruptedLog`, both with the offset the record starts at. A JSON log
+/// written by an older version of kvs is refused with `KvsError::IncompatibleStore`.
+pub fn read_record<R: Read + Seek>(
+    reader: &mut BufReaderWithPos<R>,
+    path: &std::path::Path,
+) -> Result<Option<Record>> {
+    let offset = reader.pos();
+    let corrupted = || KvsError::CorruptedLog {
+        path: path.to_path_buf(),
+        offset,
+    };
+    let truncated = || KvsError::TruncatedLog {
+        path: path.to_path_buf(),
+        offset,
+    };
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        _ if offset == 0 && header[0] == b'{' => {
+            return Err(KvsError::IncompatibleStore(format!(
+                "{} is a JSON log written by an older version of kvs",
+                path.display()
+            )))
+        }
+        RECORD_HEADER_LEN => {}
+        _ => return Err(truncated()),
+    }
+    // Check the length before trusting it to find the end of the payload.
+    let header_checksum = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if header[0..2] != RECORD_MAGIC
+        || header[2] != RECORD_VERSION
+        || crc32fast::hash(&header[2..8]) != header_checksum
+    {
+        return Err(corrupted());
+    }
+    let record_type = header[3];
+    let payload_len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let checksum = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+    let mut payload = Vec::new();
+    if reader.take(payload_len as u64).read_to_end(&mut payload)? != payload_len {
+        return Err(truncated());
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(&payload);
+    if hasher.finalize() != checksum {
+        return Err(corrupted());
+    }
+    decode_payload(record_type, &payload)?
+        .map(Some)
+        .ok_or_else(corrupted)
+}
+
+// Reads until `buf` is full or the reader runs dry, returning how much was read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            len => filled += len,
+        }
+    }
+    Ok(filled)
+}
+
+fn decode_payload(record_type: u8, payload: &[u8]) -> Result<Option<Record>> {
+    let count = || {
+        if payload.len() == 4 {
+            Some(u32::from_le_bytes([
+                payload[0], payload[1], payload[2], payload[3],
+            ]))
+        } else {
+            None
+        }
+    };
+    match record_type {
+        RECORD_TYPE_SET | RECORD_TYPE_SET_WITH_EXPIRY | RECORD_TYPE_REMOVE => {
+            Ok(decode_command(record_type, payload).map(Record::Command))
+        }
+        RECORD_TYPE_BATCH_BEGIN => Ok(count().map(Record::BatchBegin)),
+        RECORD_TYPE_BATCH_COMMIT => Ok(count().map(Record::BatchCommit)),
+        _ => Err(KvsError::UnexpectedCommandType(record_type)),
+    }
+}
+
+fn decode_command(record_type: u8, payload: &[u8]) -> Option<WriteCommand> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let key_len = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    let key = payload.get(4..4 + key_len)?.to_vec();
+    let value = &payload[4 + key_len..];
+    match record_type {
+        RECORD_TYPE_SET => Some(WriteCommand::Set(key, value.to_vec())),
+        RECORD_TYPE_SET_WITH_EXPIRY if value.len() >= 8 => {
+            let mut expires_at = [0; 8];
+            expires_at.copy_from_slice(&value[..8]);
+            Some(WriteCommand::SetWithExpiry(
+                key,
+                value[8..].to_vec(),
+                u64::from_le_bytes(expires_at),
+            ))
+        }
+        RECORD_TYPE_REMOVE if value.is_empty() => Some(WriteCommand::Remove(key)),
+        _ => None,
+    }
+}
+
+fn encode_command(command: &WriteCommand) -> Vec<u8> {
+    let (record_type, key, expires_at, value) = match command {
+        WriteCommand::Set(key, value) => (RECORD_TYPE_SET, key, None, value),
+        WriteCommand::SetWithExpiry(key, value, expires_at) => {
+            (RECORD_TYPE_SET_WITH_EXPIRY, key, Some(expires_at), value)
+        }
+        WriteCommand::Remove(key) => (RECORD_TYPE_REMOVE, key, None, &Vec::new()),
+    };
+    let mut payload = Vec::with_capacity(12 + key.len() + value.len());
+    payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    payload.extend_from_slice(key);
+    if let Some(expires_at) = expires_at {
+        payload.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    payload.extend_from_slice(value);
+    encode_record(record_type, &payload)
+}
+
+fn encode_record(record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&RECORD_MAGIC);
+    record.push(RECORD_VERSION);
+    record.push(record_type);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    let header_checksum = crc32fast::hash(&record[2..8]);
+    record.extend_from_slice(&header_checksum.to_le_bytes());
+    record.extend_from_slice(&hasher.finalize().to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
 pub struct BufReaderWithPos<R: Read + Seek> {
     reader: BufReader<R>,
     pos: u64,
@@ -33,7 +355,7 @@ pub struct BufReaderWithPos<R: Read + Seek> {
 
 impl<R: Read + Seek> BufReaderWithPos<R> {
     pub fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufReaderWithPos {
             reader: BufReader::new(inner),
             pos,
@@ -67,7 +389,7 @@ pub struct BufWriterWithPos<W: Write + Seek> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     pub fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+        let pos = inner.stream_position()?;
         Ok(BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
@@ -77,6 +399,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
     pub fn pos(&self) -> u64 {
         self.pos
     }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -105,62 +431,476 @@ pub fn log_files(path: &std::path::PathBuf) -> Result<Vec<std::path::PathBuf>> {
         .collect::<Vec<std::path::PathBuf>>())
 }
 
-pub fn sorted_log_files(path: &std::path::PathBuf) -> Result<Vec<std::path::PathBuf>> {
-    let mut log_files = log_files(path)?;
-    log_files.sort_unstable();
-    Ok(log_files)
+/// The ids of the log files in `path`, in the order they were written.
+pub fn sorted_log_ids(path: &std::path::PathBuf) -> Result<Vec<u64>> {
+    let mut log_ids = log_files(path)?
+        .iter()
+        .map(|file| log_id(file))
+        .collect::<Result<Vec<_>>>()?;
+    log_ids.sort_unstable();
+    Ok(log_ids)
 }
 
 pub fn grab_log_files(path: &std::path::PathBuf) -> Result<Vec<std::path::PathBuf>> {
     let mut entries = std::fs::read_dir(path)?
-        .map(|res| res.map(|e| e.path()).map_err(|e| Box::new(e) as _))
+        .map(|res| res.map(|e| e.path()).map_err(KvsError::from))
         .collect::<Result<Vec<std::path::PathBuf>>>()?;
     entries.sort();
     Ok(entries)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum WriteCommand {
-    Set(String, String),
-    Remove(String),
+    Set(Vec<u8>, Vec<u8>),
+    /// A set that stops being visible at the given time, in milliseconds since the
+    /// Unix epoch.
+    SetWithExpiry(Vec<u8>, Vec<u8>, u64),
+    Remove(Vec<u8>),
+}
+
+impl WriteCommand {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WriteCommand::Set(key, _)
+            | WriteCommand::SetWithExpiry(key, _, _)
+            | WriteCommand::Remove(key) => key,
+        }
+    }
+
+    pub fn kind(&self) -> RecordKind {
+        match self {
+            WriteCommand::Set(..) => RecordKind::Set,
+            WriteCommand::SetWithExpiry(_, _, expires_at) => RecordKind::SetWithExpiry(*expires_at),
+            WriteCommand::Remove(_) => RecordKind::Remove,
+        }
+    }
+
+    pub fn into_key(self) -> Vec<u8> {
+        match self {
+            WriteCommand::Set(key, _)
+            | WriteCommand::SetWithExpiry(key, _, _)
+            | WriteCommand::Remove(key) => key,
+        }
+    }
+
+    /// Whether a reader would see a value after this command.
+    pub fn is_live(&self) -> bool {
+        self.kind().is_live()
+    }
+
+    /// The value a reader should see after this command, if any.
+    pub fn into_live_value(self) -> Option<Vec<u8>> {
+        match self {
+            WriteCommand::Set(_, value) => Some(value),
+            WriteCommand::SetWithExpiry(_, value, expires_at) if !is_expired(expires_at) => {
+                Some(value)
+            }
+            WriteCommand::SetWithExpiry(..) | WriteCommand::Remove(_) => None,
+        }
+    }
+}
+
+/// Converts bytes read back from a store into a `String`, for the string-based APIs.
+pub fn into_string(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// The expiry time, in milliseconds since the Unix epoch, of an entry written now
+/// with the given time-to-live.
+pub fn expiry_after(ttl: std::time::Duration) -> u64 {
+    unix_millis()
+        .saturating_add(std::convert::TryInto::try_into(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+pub fn is_expired(expires_at: u64) -> bool {
+    expires_at <= unix_millis()
+}
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A decoded log record.
+#[derive(Debug)]
+pub enum Record {
+    Command(WriteCommand),
+    BatchBegin(u32),
+    BatchCommit(u32),
+}
+
+/// What a log record does to its key, without the key and value themselves. The
+/// index keeps it next to every pointer so live keys can be told from tombstones
+/// without going to the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Set,
+    SetWithExpiry(u64),
+    Remove,
+}
+
+impl RecordKind {
+    /// The type of the log record a command of this kind is written as.
+    pub fn record_type(&self) -> u8 {
+        match self {
+            RecordKind::Set => RECORD_TYPE_SET,
+            RecordKind::SetWithExpiry(_) => RECORD_TYPE_SET_WITH_EXPIRY,
+            RecordKind::Remove => RECORD_TYPE_REMOVE,
+        }
+    }
+
+    /// Whether a reader would see a value after a command of this kind.
+    pub fn is_live(&self) -> bool {
+        match self {
+            RecordKind::Set => true,
+            RecordKind::SetWithExpiry(expires_at) => !is_expired(*expires_at),
+            RecordKind::Remove => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogPointer {
+    pub log_id: u64,
+    pub offset: u64,
+    pub len: u64,
+    pub kind: RecordKind,
 }
 
 pub const STORE_EXT: &str = "kvslog";
+pub const COMPACTION_EXT: &str = "compacting";
+pub const HINT_EXT: &str = "hint";
+pub const HINT_MAGIC: [u8; 2] = *b"KH";
+pub const HINT_VERSION: u8 = 2;
+
+// Every log record is laid out as
+//
+//   magic (2) | version (1) | record type (1) | payload length (4, LE) |
+//   header crc32 (4, LE) | crc32 (4, LE) | payload
+//
+// where the header checksum covers the version, record type and payload length,
+// so a damaged length is caught before it is used, and the other checksum covers
+// the record type and the payload. For set and remove records the payload is the
+// key length (4, LE) followed by the raw key bytes and the raw value bytes, with
+// expiring sets putting the expiry time (8, LE, Unix milliseconds) between the key
+// and the value; batch begin and commit markers carry the number of records in
+// the batch (4, LE).
+pub const RECORD_MAGIC: [u8; 2] = *b"KV";
+pub const RECORD_VERSION: u8 = 1;
+pub const RECORD_HEADER_LEN: usize = 16;
+pub const RECORD_TYPE_SET: u8 = 1;
+pub const RECORD_TYPE_REMOVE: u8 = 2;
+pub const RECORD_TYPE_BATCH_BEGIN: u8 = 3;
+pub const RECORD_TYPE_BATCH_COMMIT: u8 = 4;
+pub const RECORD_TYPE_SET_WITH_EXPIRY: u8 = 5;
 
 #[derive(Debug)]
-pub enum KvsCommandError {
+pub enum KvsError {
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
+    NotUtf8(std::string::FromUtf8Error),
     KeyNotFound,
-    // TODO: Deserialization/Serialization errors
+    KeyExists(Vec<u8>),
+    CorruptedLog {
+        path: std::path::PathBuf,
+        offset: u64,
+    },
+    TruncatedLog {
+        path: std::path::PathBuf,
+        offset: u64,
+    },
+    UnexpectedCommandType(u8),
+    InvalidLogFile(std::path::PathBuf),
+    StoreLocked {
+        path: std::path::PathBuf,
+        pid: Option<u32>,
+    },
+    ReadOnly,
+    UnknownEngine(String),
+    Server(String),
+    IncompatibleStore(String),
 }
 
-impl std::fmt::Display for KvsCommandError {
+impl std::fmt::Display for KvsError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            KvsCommandError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Io(err) => write!(f, "IO error: {}", err),
+            KvsError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            KvsError::NotUtf8(err) => write!(f, "Stored bytes are not UTF-8: {}", err),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::KeyExists(key) => {
+                write!(f, "Key already exists: {}", String::from_utf8_lossy(key))
+            }
+            KvsError::CorruptedLog { path, offset } => write!(
+                f,
+                "Corrupted log record in {} at offset {}",
+                path.display(),
+                offset
+            ),
+            KvsError::TruncatedLog { path, offset } => write!(
+                f,
+                "Truncated log record in {} at offset {}",
+                path.display(),
+                offset
+            ),
+            KvsError::UnexpectedCommandType(record_type) => {
+                write!(f, "Unexpected command type {}", record_type)
+            }
+            KvsError::InvalidLogFile(path) => {
+                write!(f, "Invalid log file name {}", path.display())
+            }
+            KvsError::StoreLocked {
+                path,
+                pid: Some(pid),
+            } => write!(f, "Store {} is locked by pid {}", path.display(), pid),
+            KvsError::StoreLocked { path, pid: None } => {
+                write!(f, "Store {} is locked by another process", path.display())
+            }
+            KvsError::ReadOnly => write!(f, "Store is open read-only"),
+            KvsError::UnknownEngine(name) => write!(f, "Unknown engine {}", name),
+            KvsError::Server(message) => write!(f, "Server error: {}", message),
+            KvsError::IncompatibleStore(reason) => {
+                write!(f, "Incompatible store: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvsError::Io(err) => Some(err),
+            KvsError::Serialization(err) => Some(err),
+            KvsError::NotUtf8(err) => Some(err),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for KvsCommandError {}
+impl From<std::io::Error> for KvsError {
+    fn from(err: std::io::Error) -> KvsError {
+        KvsError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for KvsError {
+    fn from(err: serde_json::Error) -> KvsError {
+        KvsError::Serialization(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(err: std::string::FromUtf8Error) -> KvsError {
+        KvsError::NotUtf8(err)
+    }
+}
+
+pub fn log_id(path: &std::path::Path) -> Result<u64> {
+    path.file_stem()
+        .and_then(std::ffi::OsStr::to_str)
+        .and_then(|stem| stem.parse::<u64>().ok())
+        .ok_or_else(|| KvsError::InvalidLogFile(path.to_path_buf()))
+}
+
+pub fn log_path(path: &std::path::Path, log_id: u64) -> std::path::PathBuf {
+    path.join(format!("{}.{}", log_id, crate::utility::STORE_EXT))
+}
+
+pub fn open_log_reader(
+    path: &std::path::Path,
+    log_id: u64,
+) -> crate::utility::Result<BufReaderWithPos<std::fs::File>> {
+    BufReaderWithPos::new(
+        std::fs::OpenOptions::new()
+            .read(true)
+            .open(log_path(path, log_id))?,
+    )
+}
 
+pub fn compaction_path(path: &std::path::Path, log_id: u64) -> std::path::PathBuf {
+    path.join(format!("{}.{}", log_id, crate::utility::COMPACTION_EXT))
+}
+
+pub fn remove_compaction_files(path: &std::path::Path) -> Result<()> {
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(COMPACTION_EXT.as_ref()) {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the log files, and their hint files, in `path` that are not among the
+/// live `log_ids`.
+pub fn remove_unlisted_log_files(path: &std::path::PathBuf, log_ids: &[u64]) -> Result<()> {
+    for file in log_files(path)? {
+        if !log_ids.contains(&log_id(&file)?) {
+            log::warn!(
+                "Removing log file {} missing from the manifest",
+                file.display()
+            );
+            std::fs::remove_file(file)?;
+        }
+    }
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.is_file()
+            && file.extension() == Some(HINT_EXT.as_ref())
+            && !log_ids.contains(&log_id(&file)?)
+        {
+            std::fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn hint_path(path: &std::path::Path, log_id: u64) -> std::path::PathBuf {
+    path.join(format!("{}.{}", log_id, HINT_EXT))
+}
+
+/// Where the record for `key` sits in the log file a hint file belongs to.
+#[derive(Debug)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub kind: RecordKind,
+    pub offset: u64,
+    pub len: u64,
+}
+
+// A hint file is laid out as
+//
+//   magic (2) | version (1) | entries | crc32 (4, LE)
+//
+// where every entry is record type (1) | key length (4, LE) | offset (8, LE) |
+// record length (8, LE) | expiry time (8, LE) | key, and the checksum covers
+// everything before it. The expiry time is zero unless the record type is
+// set-with-expiry.
+pub fn write_hint_file(path: &std::path::Path, log_id: u64, entries: &[HintEntry]) -> Result<()> {
+    let mut contents = Vec::new();
+    contents.extend_from_slice(&HINT_MAGIC);
+    contents.push(HINT_VERSION);
+    for entry in entries {
+        let expires_at = match entry.kind {
+            RecordKind::SetWithExpiry(expires_at) => expires_at,
+            RecordKind::Set | RecordKind::Remove => 0,
+        };
+        contents.push(entry.kind.record_type());
+        contents.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        contents.extend_from_slice(&entry.offset.to_le_bytes());
+        contents.extend_from_slice(&entry.len.to_le_bytes());
+        contents.extend_from_slice(&expires_at.to_le_bytes());
+        contents.extend_from_slice(&entry.key);
+    }
+    let checksum = crc32fast::hash(&contents);
+    contents.extend_from_slice(&checksum.to_le_bytes());
+    let mut file = std::fs::File::create(hint_path(path, log_id))?;
+    file.write_all(&contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Rebuilds the index of log file `log_id` from its hint file without reading any
+/// values. Returns `None` if there is no hint file or it cannot be trusted, in
+/// which case the log file itself has to be parsed.
+pub fn read_hint_file(
+    path: &std::path::Path,
+    log_id: u64,
+) -> Result<Option<std::collections::HashMap<Vec<u8>, LogPointer>>> {
+    let hint_path = hint_path(path, log_id);
+    let contents = match std::fs::read(&hint_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let index = decode_hints(&contents, log_id);
+    if index.is_none() {
+        log::warn!("Ignoring invalid hint file {}", hint_path.display());
+    }
+    Ok(index)
+}
+
+fn decode_hints(
+    contents: &[u8],
+    log_id: u64,
+) -> Option<std::collections::HashMap<Vec<u8>, LogPointer>> {
+    let body_len = contents.len().checked_sub(4)?;
+    let (body, checksum) = contents.split_at(body_len);
+    if body.len() < 3
+        || body[..2] != HINT_MAGIC
+        || body[2] != HINT_VERSION
+        || crc32fast::hash(body).to_le_bytes() != checksum
+    {
+        return None;
+    }
+    let u64_at = |pos: usize| -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(body.get(pos..pos + 8)?);
+        Some(u64::from_le_bytes(bytes))
+    };
+    let mut index = std::collections::HashMap::new();
+    let mut pos = 3;
+    while pos < body.len() {
+        let mut key_len = [0; 4];
+        key_len.copy_from_slice(body.get(pos + 1..pos + 5)?);
+        let key_len = u32::from_le_bytes(key_len) as usize;
+        let offset = u64_at(pos + 5)?;
+        let len = u64_at(pos + 13)?;
+        let kind = match body[pos] {
+            RECORD_TYPE_SET => RecordKind::Set,
+            RECORD_TYPE_SET_WITH_EXPIRY => RecordKind::SetWithExpiry(u64_at(pos + 21)?),
+            RECORD_TYPE_REMOVE => RecordKind::Remove,
+            _ => return None,
+        };
+        let key = body.get(pos + 29..pos + 29 + key_len)?;
+        index.insert(
+            key.to_vec(),
+            LogPointer {
+                log_id,
+                offset,
+                len,
+                kind,
+            },
+        );
+        pos += 29 + key_len;
+    }
+    Some(index)
+}
+
+pub fn sync_dir(path: &std::path::Path) -> Result<()> {
+    #[cfg(unix)]
+    std::fs::File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Creates the log file for generation `log_id`, failing if it already exists.
 pub fn new_log_file(
     path: &std::path::Path,
+    log_id: u64,
 ) -> crate::utility::Result<BufWriterWithPos<std::fs::File>> {
-    let path = path.join(format!(
-        "{}.{}",
-        std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)?
-            .as_micros(),
-        crate::utility::STORE_EXT
-    ));
-    Ok(crate::utility::BufWriterWithPos::new(
+    create_log_file(&log_path(path, log_id))
+}
+
+pub fn new_compaction_file(
+    path: &std::path::Path,
+    log_id: u64,
+) -> crate::utility::Result<BufWriterWithPos<std::fs::File>> {
+    create_log_file(&compaction_path(path, log_id))
+}
+
+fn create_log_file(path: &std::path::Path) -> Result<BufWriterWithPos<std::fs::File>> {
+    crate::utility::BufWriterWithPos::new(
         std::fs::OpenOptions::new()
             .read(true)
             .append(true)
-            .write(true)
-            .create(true)
-            .open(&path)?,
-    )?)
+            .create_new(true)
+            .open(path)?,
+    )
 }
 
 pub fn write_command<W: std::io::Write + std::io::Seek>(
@@ -168,8 +908,27 @@ pub fn write_command<W: std::io::Write + std::io::Seek>(
     command: &crate::utility::WriteCommand,
 ) -> crate::utility::Result<u64> {
     let begin_pos = writer.pos();
-    serde_json::to_writer(&mut *writer, &command)?;
+    writer.write_all(&encode_command(command))?;
     std::io::Write::flush(&mut *writer)?;
     let end_pos = writer.pos();
     Ok(end_pos - begin_pos)
 }
+
+/// Writes `commands` framed by batch begin and commit markers, flushing once at the
+/// end. Returns the offset and length of each command's record.
+pub fn write_batch<W: std::io::Write + std::io::Seek>(
+    writer: &mut crate::utility::BufWriterWithPos<W>,
+    commands: &[crate::utility::WriteCommand],
+) -> crate::utility::Result<Vec<(u64, u64)>> {
+    let count = (commands.len() as u32).to_le_bytes();
+    writer.write_all(&encode_record(RECORD_TYPE_BATCH_BEGIN, &count))?;
+    let mut records = Vec::with_capacity(commands.len());
+    for command in commands {
+        let offset = writer.pos();
+        writer.write_all(&encode_command(command))?;
+        records.push((offset, writer.pos() - offset));
+    }
+    writer.write_all(&encode_record(RECORD_TYPE_BATCH_COMMIT, &count))?;
+    std::io::Write::flush(&mut *writer)?;
+    Ok(records)
+}
@@ -1,5 +1,14 @@
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{kvs::KvStore, utility::Result};
+use kvs::{
+    batch::{OnExisting, WriteBatch},
+    compaction::{GarbageBytes, SealedSegments},
+    engine::{Engine, Stats},
+    kvs::{CompactionStep, Durability, KvStore},
+    manifest::Manifest,
+    utility::{KvsError, Result},
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
 use std::process::Command;
@@ -64,7 +73,7 @@ fn cli_set() {
 #[test]
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     Command::cargo_bin("kvs")
@@ -91,7 +100,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -114,6 +123,316 @@ fn cli_rm_stored() -> Result<()> {
     Ok(())
 }
 
+// `kvs set` should fail while another process has the store open, but `kvs get`
+// only needs read access.
+#[test]
+fn cli_store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("locked by pid {}", std::process::id())));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Ok(())
+}
+
+// `kvs set <KEY> --file <PATH>` should store the file contents, with `-` reading stdin.
+#[test]
+fn cli_set_from_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("value.txt"), "value1")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "--file", "value.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key2", "--file", "-"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("value2")
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Conditional writes should exit with 2 when their precondition does not hold.
+#[test]
+fn cli_conditional_writes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs = |args: &[&str]| {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(args)
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    kvs(&["set-if-present", "key1", "value1"])
+        .code(2)
+        .stdout(eq("Precondition failed").trim());
+    kvs(&["set-if-absent", "key1", "value1"])
+        .success()
+        .stdout(is_empty());
+    kvs(&["set-if-absent", "key1", "value2"])
+        .code(2)
+        .stdout(eq("Precondition failed").trim());
+    kvs(&["set-if-present", "key1", "value2"]).success();
+    kvs(&["cas", "key1", "--expected", "value1", "--new", "value3"])
+        .code(2)
+        .stdout(eq("Precondition failed").trim());
+    kvs(&["cas", "key1", "--expected", "value2", "--new", "value3"]).success();
+    kvs(&["get", "key1"]).success().stdout(eq("value3").trim());
+    kvs(&["cas", "key1", "--expected", "value3"]).success();
+    kvs(&["get", "key1"])
+        .success()
+        .stdout(eq("Key not found").trim());
+    kvs(&["cas", "key1", "--new", "value4"]).success();
+    kvs(&["get", "key1"]).success().stdout(eq("value4").trim());
+}
+
+// `kvs scan --prefix <PREFIX>` should list the matching keys and values in key order.
+#[test]
+fn cli_scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:2:name".to_owned(), "bob".to_owned())?;
+    store.set("user:1:name".to_owned(), "alice".to_owned())?;
+    store.set("group:1:name".to_owned(), "admins".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--prefix", "user:"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1:name\talice\nuser:2:name\tbob\n"));
+
+    Ok(())
+}
+
+// `kvs stats` should print the store statistics as text or as JSON.
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys:       1\n"))
+        .stdout(contains("last compaction: never\n"));
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["stats", "--json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let stats: Stats = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.log_files, 1);
+    Ok(())
+}
+
+// `kvs check` should list every record, report damaged records by file and offset
+// without touching them, and salvage the readable ones into a new store.
+#[test]
+fn cli_check() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(&store_dir)?;
+    store.set("key1".to_owned(), "v1".to_owned())?;
+    store.set("key2".to_owned(), "v2".to_owned())?;
+    store.set("key3".to_owned(), "v3".to_owned())?;
+    store.set("key1".to_owned(), "v4".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["check", "--path"])
+        .arg(&store_dir)
+        .assert()
+        .success()
+        .stdout(contains("1.kvslog:0\t26\tset\tkey1\n"))
+        .stdout(contains("1.kvslog:78\t26\tset\tkey1\n"))
+        .stdout(contains("duplicate keys:   1 (1 superseded records)\n"));
+
+    // Damage the key of the second record, which takes up bytes 26 to 52.
+    let log_file = store_dir.join("1.kvslog");
+    let mut log = std::fs::read(&log_file)?;
+    log[46] ^= 0xff;
+    std::fs::write(&log_file, &log)?;
+
+    let salvage_dir = temp_dir.path().join("salvaged");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["check", "--path"])
+        .arg(&store_dir)
+        .arg("--salvage")
+        .arg(&salvage_dir)
+        .assert()
+        .failure()
+        .stdout(contains("1.kvslog:26\t26\tunreadable\n"))
+        .stdout(contains("1.kvslog:52\t26\tset\tkey3\n"))
+        .stdout(contains("Corruption found\n"));
+    assert_eq!(std::fs::read(&log_file)?, log);
+
+    let store = KvStore::open(&salvage_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("v4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("v3".to_owned()));
+    Ok(())
+}
+
+// `kvs export` should write a dump that `kvs import` loads into another store.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(source_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes(b"key2", &[0, 159, 146, 150])?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["export"])
+        .current_dir(&source_dir)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout.clone())?,
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\",\"value\":[0,159,146,150]}\n"
+    );
+
+    let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key1", "old"])
+        .current_dir(&dest_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["import"])
+        .current_dir(&dest_dir)
+        .with_stdin()
+        .buffer(output.stdout.clone())
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists: key1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["import", "--overwrite", "--skip-existing"])
+        .current_dir(&dest_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["import", "--overwrite"])
+        .current_dir(&dest_dir)
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let store = KvStore::open(dest_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_bytes(b"key2")?, Some(vec![0, 159, 146, 150]));
+    Ok(())
+}
+
+// `kvs --durability <MODE> set <KEY> <VALUE>` should store the value with every mode.
+#[test]
+fn cli_set_durability() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for (idx, mode) in ["always", "interval", "never"].iter().enumerate() {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["--durability", mode, "set", &format!("key{}", idx), "value"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--durability", "sometimes", "set", "key", "value"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let store = KvStore::open(temp_dir.path())?;
+    for idx in 0..3 {
+        assert_eq!(store.get(format!("key{}", idx))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// `kvs --engine <ENGINE>` should accept the known engines only.
+#[test]
+fn cli_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for engine in Engine::ALL.iter() {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["--engine", engine.name(), "get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--engine", "unknown", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
@@ -178,7 +497,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -199,7 +518,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -208,7 +527,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -220,7 +539,7 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
@@ -233,10 +552,32 @@ fn get_non_existent_value() -> Result<()> {
     Ok(())
 }
 
+// Every durability mode should persist writes across a reopen.
+#[test]
+fn durability_modes() -> Result<()> {
+    for durability in &[
+        Durability::Always,
+        Durability::Interval(std::time::Duration::from_millis(10)),
+        Durability::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_durability(temp_dir.path(), *durability)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with_durability(temp_dir.path(), *durability)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -244,55 +585,1555 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Every engine should remove keys the same way and refuse to remove missing ones.
 #[test]
-fn compaction() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+fn engine_remove_key() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove("key1".to_owned()).is_ok());
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+// Every engine should store, overwrite and miss keys the same way.
+#[test]
+fn engine_set_get() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+    Ok(())
+}
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+// Scans should return live pairs in key order and skip removed keys.
+#[test]
+fn scan_keys() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        for key in ["b", "a", "ab", "abc", "b\u{10ffff}", "c"] {
+            store.set(key.to_owned(), format!("value-{}", key))?;
         }
+        store.remove("abc".to_owned())?;
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
-        }
-        // Compaction triggered.
+        let keys = |scan: kvs::engine::Scan| scan.map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(
+            keys(store.scan_prefix("a")?),
+            vec!["a".to_owned(), "ab".to_owned()]
+        );
+        assert_eq!(
+            keys(store.scan_prefix("b")?),
+            vec!["b".to_owned(), "b\u{10ffff}".to_owned()]
+        );
+        assert_eq!(keys(store.scan_prefix("")?).len(), 5);
+        assert_eq!(
+            keys(store.scan(
+                std::ops::Bound::Included("ab".to_owned()),
+                std::ops::Bound::Excluded("c".to_owned())
+            )?),
+            vec!["ab".to_owned(), "b".to_owned(), "b\u{10ffff}".to_owned()]
+        );
+        assert_eq!(
+            store.scan_prefix("ab")?.collect::<Vec<_>>(),
+            vec![("ab".to_owned(), "value-ab".to_owned())]
+        );
 
-        drop(store);
-        // reopen and check content.
-        let store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        // Ranges that start past their end are empty rather than a panic that
+        // would poison the store for every clone.
+        for (start, end) in [
+            (
+                std::ops::Bound::Included("b".to_owned()),
+                std::ops::Bound::Included("a".to_owned()),
+            ),
+            (
+                std::ops::Bound::Excluded("b".to_owned()),
+                std::ops::Bound::Excluded("b".to_owned()),
+            ),
+            (
+                std::ops::Bound::Included("b".to_owned()),
+                std::ops::Bound::Excluded("b".to_owned()),
+            ),
+        ] {
+            assert_eq!(store.scan(start.clone(), end.clone())?.count(), 0);
+            assert!(store
+                .scan_keys(
+                    start.map(String::into_bytes),
+                    end.map(String::into_bytes),
+                    10
+                )?
+                .is_empty());
         }
-        return Ok(());
+        assert_eq!(store.get("b".to_owned())?, Some("value-b".to_owned()));
     }
 
-    panic!("No compaction detected");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let reversed = "key2".to_owned().."key1".to_owned();
+    assert_eq!(store.scan(reversed)?.count(), 0);
+    assert_eq!(
+        store.scan("key1".to_owned()..)?.collect::<Vec<_>>(),
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, and reading non-UTF-8 data through the
+// string-based methods should fail instead of mangling it.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let key = [0u8, 159, 146, 150, 255];
+    let value = [255u8, 0, 1, 2, 0xc3, 0x28];
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        store.set_bytes(&key, &value)?;
+        store.set_bytes(b"text", &value)?;
+        assert_eq!(store.get_bytes(&key)?, Some(value.to_vec()));
+        assert!(matches!(
+            store.get("text".to_owned()),
+            Err(KvsError::NotUtf8(_))
+        ));
+        assert_eq!(
+            store.scan_prefix_bytes(&[0])?.collect::<Vec<_>>(),
+            vec![(key.to_vec(), value.to_vec())]
+        );
+        store.remove_bytes(&key)?;
+        assert_eq!(store.get_bytes(&key)?, None);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(&key, &value)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.to_vec()));
+    Ok(())
+}
+
+// Conditional writes should only go through when the current value matches.
+#[test]
+fn conditional_writes() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        assert!(!store.set_if_present("key1".to_owned(), "value1".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+        assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(store.set_if_present("key1".to_owned(), "value2".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        assert!(!store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value3".to_owned())
+        )?);
+        assert!(!store.compare_and_swap("key1".to_owned(), None, Some("value3".to_owned()))?);
+        assert!(store.compare_and_swap(
+            "key1".to_owned(),
+            Some("value2".to_owned()),
+            Some("value3".to_owned())
+        )?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert!(store.compare_and_swap("key1".to_owned(), Some("value3".to_owned()), None)?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(store.compare_and_swap("key1".to_owned(), None, None)?);
+        assert!(store.compare_and_swap("key1".to_owned(), None, Some("value4".to_owned()))?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    }
+    Ok(())
+}
+
+// Concurrent compare-and-swap increments should never lose an update.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let handles = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < 50 {
+                    let current = store.get("counter".to_owned())?.unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    if store.compare_and_swap("counter".to_owned(), Some(current), Some(next))? {
+                        done += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Only one writable store may be open on a directory at a time, and the lock is
+// held until the last clone is dropped.
+#[test]
+fn store_locked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    drop(store);
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("second open not rejected");
+    assert!(matches!(
+        err,
+        KvsError::StoreLocked { pid: Some(pid), .. } if pid == std::process::id()
+    ));
+
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A read-only store should work next to a writer, reject writes and leave a torn
+// write in place.
+#[test]
+fn read_only_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        reader.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        reader.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    drop(reader);
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 3)?;
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader.get("key2".to_owned())?, None);
+    assert_eq!(std::fs::metadata(&log_file)?.len(), len - 3);
+
+    // A store that was never written to reads as empty and is not created.
+    let missing = temp_dir.path().join("missing");
+    let reader = KvStore::open_read_only(&missing)?;
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert!(!missing.exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["--path"])
+        .arg(&missing)
+        .args(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// A write batch should apply all of its commands and survive a reopen.
+#[test]
+fn write_batch() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key2".to_owned(), "value2".to_owned())
+            .remove("key1".to_owned())
+            .set("key3".to_owned(), "value3".to_owned())
+            .remove("key3".to_owned());
+        store.write_batch(batch)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value1".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A batch that removes a missing key should leave the store untouched.
+#[test]
+fn write_batch_missing_key() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key1".to_owned(), "value1".to_owned())
+            .remove("key2".to_owned());
+        assert!(matches!(
+            store.write_batch(batch),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Sets of existing keys should be written, skipped or refused according to the
+// batch's `OnExisting`, with the lookup done as the batch is committed.
+#[test]
+fn write_batch_on_existing() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let batch = |on_existing| {
+            let mut batch = WriteBatch::new();
+            batch
+                .on_existing(on_existing)
+                .set("key1".to_owned(), "value2".to_owned())
+                .set("key2".to_owned(), "value2".to_owned());
+            batch
+        };
+
+        assert!(matches!(
+            store.write_batch(batch(OnExisting::Fail)),
+            Err(KvsError::KeyExists(key)) if key == b"key1"
+        ));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.write_batch(batch(OnExisting::Skip))?, 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.write_batch(batch(OnExisting::Skip))?, 0);
+        assert_eq!(store.write_batch(batch(OnExisting::Overwrite))?, 2);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// A batch cut short by a crash before its commit marker should be dropped entirely.
+#[test]
+fn skip_incomplete_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut off the 20 byte commit marker so the file ends on a complete record.
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 20)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// Salvage should drop a batch without its commit marker, as opening the store does.
+#[test]
+fn salvage_skips_incomplete_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    let store = KvStore::open(&store_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut off the 20 byte commit marker.
+    let log_file = non_empty_log_files(&store_dir).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 20)?;
+
+    let salvage_dir = temp_dir.path().join("salvaged");
+    let files = kvs::check::check_store(&store_dir)?;
+    assert_eq!(kvs::check::salvage(&files, &salvage_dir)?, 1);
+    let store = KvStore::open(&salvage_dir)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Keys set with a time-to-live should read as absent once it has passed.
+#[test]
+fn expire_keys() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        store.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            std::time::Duration::from_millis(50),
+        )?;
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            std::time::Duration::from_secs(3600),
+        )?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.scan_prefix("key")?.count(), 1);
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Expiry times are stored in the log, so they should survive a reopen.
+#[test]
+fn expire_keys_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        std::time::Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Compaction should leave expired entries out of the compacted log.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "session".to_owned(),
+        "token".to_owned(),
+        std::time::Duration::from_millis(1),
+    )?;
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    for log_file in non_empty_log_files(temp_dir.path()) {
+        let contents = std::fs::read(&log_file)?;
+        assert!(!contents.windows(7).any(|window| window == b"session"));
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("session".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("999".to_owned()));
+    Ok(())
+}
+
+// Expired entries are garbage, so they should count as wasted space and start a
+// compaction on their own, and the in-memory engine should drop them.
+#[test]
+fn compact_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            std::time::Duration::from_millis(1),
+        )?;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let stats = store.stats()?;
+    assert_eq!(stats.tombstones, 100);
+    assert_eq!(stats.wasted_bytes, stats.total_bytes);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.wasted_bytes, stats.total_bytes);
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "value".to_owned(),
+            std::time::Duration::from_millis(1),
+        )?;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.tombstones < 2000);
+
+    let store = Engine::Memory.open(temp_dir.path(), Durability::default())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        std::time::Duration::from_millis(1),
+    )?;
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(store.stats()?.tombstones, 1);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.tombstones, 0);
+    Ok(())
+}
+
+// Log files should be numbered by generation and listed in the manifest.
+#[test]
+fn manifest_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert_eq!(manifest, Manifest::new(vec![1, 2]));
+    assert_eq!(
+        non_empty_log_files(temp_dir.path()),
+        vec![
+            temp_dir.path().join("1.kvslog"),
+            temp_dir.path().join("2.kvslog")
+        ]
+    );
+    Ok(())
+}
+
+// Log files missing from the manifest are leftovers and should not be replayed.
+#[test]
+fn manifest_ignores_unlisted_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("9.kvslog"), "garbage")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("9.kvslog").exists());
+    Ok(())
+}
+
+// Stores written before manifests existed should be opened from a directory listing.
+#[test]
+fn manifest_created_for_old_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(
+        Manifest::load(temp_dir.path())?.map(|manifest| manifest.generations),
+        Some(vec![1, 2])
+    );
+    Ok(())
+}
+
+// A store written by another engine or format version should be refused.
+#[test]
+fn manifest_incompatible_store() -> Result<()> {
+    for manifest in [
+        r#"{"format_version":1,"engine":"sled","generations":[]}"#,
+        r#"{"format_version":2,"engine":"kvs","generations":[]}"#,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        std::fs::write(temp_dir.path().join("MANIFEST"), manifest)?;
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(KvsError::IncompatibleStore(_))
+        ));
+    }
+    Ok(())
+}
+
+// A store of JSON logs, as older versions of kvs wrote them, should be refused as
+// incompatible rather than reported as corrupted.
+#[test]
+fn refuse_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1603000000000000.kvslog"),
+        r#"{"Set":["key1","value1"]}{"Remove":"key1"}"#,
+    )?;
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::IncompatibleStore(_))
+    ));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::IncompatibleStore(_))
+    ));
+    Ok(())
+}
+
+// Compaction should leave a hint file next to the compacted log, which open reads
+// instead of the log itself, falling back to the log when the hint is unusable.
+#[test]
+fn compaction_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // Stopping after the stale files are removed leaves a complete compaction.
+    store.crash_during_compaction(CompactionStep::RemoveStale)?;
+
+    let hint_files = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect::<Vec<_>>();
+    assert_eq!(hint_files.len(), 1);
+    let hint_file = &hint_files[0];
+    let log_file = hint_file.with_extension("kvslog");
+
+    // Corrupt a value: only reading the log file itself can notice.
+    let mut bytes = std::fs::read(&log_file)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(&log_file, bytes)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    drop(store);
+
+    let mut bytes = std::fs::read(hint_file)?;
+    bytes[3] ^= 0x01;
+    std::fs::write(hint_file, bytes)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptedLog { .. })
+    ));
+    Ok(())
+}
+
+// Compaction should not carry tombstones forward, since it rewrites every older
+// generation.
+#[test]
+fn compaction_drops_tombstones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..9 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.crash_during_compaction(CompactionStep::RemoveStale)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    assert!(matches!(
+        store.remove("key0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+    let compacted = non_empty_log_files(temp_dir.path());
+    assert_eq!(compacted.len(), 1);
+    let log = std::fs::read(&compacted[0])?;
+    for key_id in 0..9 {
+        let key = format!("key{}", key_id);
+        assert!(!log
+            .windows(key.len())
+            .any(|window| window == key.as_bytes()));
+    }
+    Ok(())
+}
+
+// A few overwrites on a large store should not be enough garbage to compact.
+#[test]
+fn compaction_tracks_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    drop(store);
+    assert_eq!(
+        Manifest::load(temp_dir.path())?.expect("no manifest written"),
+        Manifest::new(vec![1])
+    );
+
+    // Once half of the log is garbage, a compaction should have run.
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("newer{}", key_id))?;
+    }
+    drop(store);
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert!(!manifest.generations.contains(&1));
+    Ok(())
+}
+
+// The configured compaction policy, not a fixed waste ratio, should decide when
+// writes start a compaction.
+#[test]
+fn compaction_policies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    assert_eq!(
+        Manifest::load(temp_dir.path())?.expect("no manifest written"),
+        Manifest::new(vec![1])
+    );
+
+    // Removes count as writes too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(0))
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.remove("key".to_owned())?;
+    drop(store);
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert!(!manifest.generations.contains(&1));
+
+    // Rolling over to new segments should compact without any garbage.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_segment_size(1024)
+        .compaction_policy(SealedSegments(2))
+        .open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    assert!(!manifest.generations.contains(&1));
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// `compact` should rewrite the store into a single log file on demand.
+#[test]
+fn compact_on_demand() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let size_before = std::fs::metadata(&log_file)?.len();
+
+    store.compact()?;
+    let log_files = non_empty_log_files(temp_dir.path());
+    assert_eq!(log_files.len(), 1);
+    assert!(std::fs::metadata(&log_files[0])?.len() * 50 < size_before);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    Ok(())
+}
+
+// `stats` should count live keys and tombstones and add up the log files, both
+// before and after a compaction.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.total_bytes, std::fs::metadata(&log_file)?.len());
+    assert!(stats.wasted_bytes > 0);
+    assert_eq!(stats.log_files, 1);
+    assert_eq!(stats.last_compaction, None);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.wasted_bytes, 0);
+    assert_eq!(stats.log_files, 2);
+    assert!(stats.last_compaction.is_some());
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.stats()?, stats);
+    Ok(())
+}
+
+// Once every key is removed, both the old records and the tombstones that hide them
+// are garbage, in the running store and after a reopen.
+#[test]
+fn stats_count_tombstones_as_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(GarbageBytes(u64::MAX))
+        .open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.wasted_bytes, stats.total_bytes);
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.wasted_bytes, stats.total_bytes);
+    Ok(())
+}
+
+// Expiry times are kept in the index and the hint files, so stats should count a key
+// as a tombstone once it expires, also after reopening a compacted store.
+#[test]
+fn stats_count_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        std::time::Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.live_keys, 3);
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.tombstones, 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.tombstones, 1);
+    Ok(())
+}
+
+// A dump should carry every live pair over to a store of any engine, with
+// existing keys handled according to `OnExisting`.
+#[test]
+fn export_import() -> Result<()> {
+    for source_engine in Engine::ALL {
+        for dest_engine in Engine::ALL {
+            let source_dir = TempDir::new().expect("unable to create temporary working directory");
+            let source = source_engine.open(source_dir.path(), Durability::default())?;
+            source.set("key1".to_owned(), "value1".to_owned())?;
+            source.set("key2".to_owned(), "value2".to_owned())?;
+            source.set_bytes(&[0xff], &[0, 159])?;
+            source.set("removed".to_owned(), "value".to_owned())?;
+            source.remove("removed".to_owned())?;
+            let mut dump = Vec::new();
+            assert_eq!(kvs::dump::export(&*source, &mut dump)?, 3);
+
+            let dest_dir = TempDir::new().expect("unable to create temporary working directory");
+            let dest = dest_engine.open(dest_dir.path(), Durability::default())?;
+            dest.set("key1".to_owned(), "old".to_owned())?;
+            assert!(matches!(
+                kvs::dump::import(&*dest, &dump[..], OnExisting::Fail),
+                Err(KvsError::KeyExists(key)) if key == b"key1"
+            ));
+            assert_eq!(dest.get("key2".to_owned())?, None);
+
+            assert_eq!(kvs::dump::import(&*dest, &dump[..], OnExisting::Skip)?, 2);
+            assert_eq!(dest.get("key1".to_owned())?, Some("old".to_owned()));
+            assert_eq!(
+                kvs::dump::import(&*dest, &dump[..], OnExisting::Overwrite)?,
+                3
+            );
+            assert_eq!(
+                dest.scan_bytes(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)?
+                    .collect::<Vec<_>>(),
+                source
+                    .scan_bytes(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)?
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+    Ok(())
+}
+
+// An export larger than the chunks it reads keys in should still hold every live
+// pair once, in key order.
+#[test]
+fn export_in_chunks() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = engine.open(temp_dir.path(), Durability::default())?;
+        for key_id in 0..2500 {
+            store.set(format!("key{:04}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key1024".to_owned())?;
+        let mut dump = Vec::new();
+        assert_eq!(kvs::dump::export(&*store, &mut dump)?, 2499);
+        let keys = serde_json::Deserializer::from_slice(&dump)
+            .into_iter::<kvs::dump::Entry>()
+            .map(|entry| Ok(Vec::from(entry?.key)))
+            .collect::<std::result::Result<Vec<_>, serde_json::Error>>()
+            .expect("invalid dump");
+        let expected = (0..2500)
+            .filter(|key_id| *key_id != 1024)
+            .map(|key_id| format!("key{:04}", key_id).into_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+    }
+    Ok(())
+}
+
+// Writes should roll over to a new log file once the active one passes the
+// configured segment size, and every segment should be read back on open.
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_segment_size(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let manifest = Manifest::load(temp_dir.path())?.expect("no manifest written");
+    let log_files = non_empty_log_files(temp_dir.path());
+    assert!(log_files.len() > 1);
+    assert_eq!(
+        log_files,
+        manifest
+            .generations
+            .iter()
+            .map(|log_id| temp_dir.path().join(format!("{}.kvslog", log_id)))
+            .collect::<Vec<_>>()
+    );
+    for sealed in &log_files[..log_files.len() - 1] {
+        assert!(std::fs::metadata(sealed)?.len() >= 1024);
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .filter_map(|res| match res.and_then(|entry| entry.metadata()) {
+                Ok(metadata) => Some(Ok(metadata.len())),
+                // A background compaction can remove a file while it is walked.
+                Err(err)
+                    if err.io_error().map(std::io::Error::kind)
+                        == Some(std::io::ErrorKind::NotFound) =>
+                {
+                    None
+                }
+                Err(err) => Some(Err(err)),
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered.
+
+        drop(store);
+        // reopen and check content.
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// A flipped bit in a log record should be reported as corruption when reopening.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path())
+        .into_iter()
+        .next()
+        .expect("no log file written");
+    let mut bytes = std::fs::read(&log_file)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    std::fs::write(&log_file, bytes)?;
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    assert!(matches!(err, KvsError::CorruptedLog { path, .. } if path == log_file));
+    Ok(())
+}
+
+// A record cut short at the end of the newest log file should be dropped on open.
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(std::fs::metadata(&log_file)?.len() < len - 3);
+    Ok(())
+}
+
+// A damaged length field in the middle of the newest log file is not a torn write, even
+// when it makes the record run past the end of the file, and should fail the open
+// without cutting the file back.
+#[test]
+fn reject_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let original = std::fs::read(&log_file)?;
+    let record_len = original.len() / 5;
+    let length_field = 2 * record_len + 4..2 * record_len + 8;
+
+    // A flipped length byte fails the header checksum.
+    let mut bytes = original.clone();
+    bytes[length_field.start + 2] ^= 0x01;
+    std::fs::write(&log_file, &bytes)?;
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    assert!(matches!(err, KvsError::CorruptedLog { path, .. } if path == log_file));
+    assert_eq!(std::fs::read(&log_file)?, bytes);
+
+    // A length that passes the header checksum still runs into the records after it.
+    let mut bytes = original.clone();
+    bytes[length_field.clone()].copy_from_slice(&(original.len() as u32).to_le_bytes());
+    let header_checksum = crc32fast::hash(&bytes[2 * record_len + 2..2 * record_len + 8]);
+    bytes[length_field.end..length_field.end + 4].copy_from_slice(&header_checksum.to_le_bytes());
+    std::fs::write(&log_file, &bytes)?;
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("corruption not detected");
+    assert!(matches!(err, KvsError::CorruptedLog { path, .. } if path == log_file));
+    assert_eq!(std::fs::read(&log_file)?, bytes);
+    Ok(())
+}
+
+// `kvs` should log a warning when it cuts a torn write off the newest log file.
+#[test]
+fn cli_warn_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 3)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["set", "key3", "value3"])
+        .current_dir(&temp_dir)
+        .env_remove("RUST_LOG")
+        .assert()
+        .success()
+        .stderr(contains("Truncating torn write"));
+    Ok(())
+}
+
+// A truncated record in an older log file is not a torn write and should fail the open.
+#[test]
+fn reject_truncated_older_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_file = non_empty_log_files(temp_dir.path()).remove(0);
+    let len = std::fs::metadata(&log_file)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_file)?
+        .set_len(len - 3)?;
+
+    let err = KvStore::open(temp_dir.path())
+        .err()
+        .expect("truncation not detected");
+    assert!(matches!(err, KvsError::TruncatedLog { path, .. } if path == log_file));
+    Ok(())
+}
+
+fn non_empty_log_files(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut files = WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.is_file()
+                && path.extension() == Some("kvslog".as_ref())
+                && std::fs::metadata(path)
+                    .map(|m| m.len() > 0)
+                    .unwrap_or(false)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+// Whatever step a compaction dies at, reopening should see the same data.
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for step in &[
+        CompactionStep::WriteCompacted,
+        CompactionStep::SyncCompacted,
+        CompactionStep::RenameCompacted,
+        CompactionStep::InstallCompacted,
+        CompactionStep::RemoveStale,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), "old".to_owned())?;
+        }
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key0".to_owned(), "new".to_owned())?;
+        store.set("key1".to_owned(), "new".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.crash_during_compaction(*step)?;
+        let listed = |path: &std::path::Path| -> Result<bool> {
+            let generations = Manifest::load(temp_dir.path())?
+                .expect("no manifest written")
+                .generations;
+            Ok(path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok())
+                .is_some_and(|log_id| generations.contains(&log_id)))
+        };
+        let log_files = || {
+            WalkDir::new(temp_dir.path())
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.into_path())
+                .filter(|path| {
+                    [Some("kvslog".as_ref()), Some("hint".as_ref())].contains(&path.extension())
+                })
+                .collect::<Vec<_>>()
+        };
+        if *step == CompactionStep::RenameCompacted {
+            // The compacted file is in place, but the manifest does not list it yet.
+            assert!(log_files().iter().any(|path| !listed(path).unwrap()));
+        }
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .all(|entry| entry.path().extension() != Some("compacting".as_ref())));
+        for path in log_files() {
+            assert!(listed(&path)?, "{} is not in the manifest", path.display());
+        }
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        for key_id in 3..10 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("old".to_owned()));
+        }
+        store.set("key3".to_owned(), "after".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("after".to_owned()));
+    }
+    Ok(())
+}
+
+// Clones of one store should be usable from many threads at once.
+#[test]
+fn concurrent_clones() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+    assert_shareable::<KvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 0..100 {
+                    let key = format!("key{}_{}", thread_id, iter % 10);
+                    let value = format!("{}", iter);
+                    store.set(key.clone(), value.clone())?;
+                    assert_eq!(store.get(key)?, Some(value));
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("{}", 90 + key_id))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Writes and removes from other clones should go on while a background compaction
+// rewrites the same keys, and the newest write should win both in memory and
+// after a reopen.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let padding = "x".repeat(1000);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("0{}", padding))?;
+    }
+
+    // Every round turns the previous one into garbage, so compactions keep starting
+    // in the background while the other threads write.
+    let handles = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            let padding = padding.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for round in 1..=5 {
+                    for key_id in (thread_id..1000).step_by(4) {
+                        store.set(format!("key{}", key_id), format!("{}{}", round, padding))?;
+                    }
+                }
+                for key_id in (thread_id..1000)
+                    .step_by(4)
+                    .filter(|key_id| key_id % 3 == 0)
+                {
+                    store.remove(format!("key{}", key_id))?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id % 3 == 0 {
+                None
+            } else {
+                Some(format!("5{}", padding))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+    // Dropping the store waits for a compaction still running in the background.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats()?.last_compaction.is_some());
+    check(&store)
+}
+
+// The server should keep the expiry time of expiring sets, and answer a request it
+// cannot parse with an error before closing the connection.
+#[test]
+fn server_protocol() -> Result<()> {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let server = kvs::server::KvsServer::new(std::sync::Arc::new(kvs::memory::MemStore::new()));
+    std::thread::spawn({
+        let addr = addr.clone();
+        move || server.run(addr)
+    });
+    let connect = || loop {
+        if let Ok(stream) = std::net::TcpStream::connect(&addr) {
+            return stream;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    };
+
+    let stream = connect();
+    let mut responses =
+        serde_json::Deserializer::from_reader(&stream).into_iter::<kvs::protocol::Response>();
+    let expires_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+        + 60_000;
+    for request in [
+        kvs::protocol::Request::Write(kvs::utility::WriteCommand::SetWithExpiry(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            expires_at,
+        )),
+        kvs::protocol::Request::Get(b"key1".to_vec()),
+    ] {
+        serde_json::to_writer(&stream, &request)?;
+    }
+    assert!(matches!(
+        responses.next().unwrap()?,
+        kvs::protocol::Response::Done
+    ));
+    assert!(matches!(
+        responses.next().unwrap()?,
+        kvs::protocol::Response::Value(Some(value)) if value == b"value1"
+    ));
+
+    let mut stream = connect();
+    std::io::Write::write_all(&mut stream, b"{\"Bogus\":1}")?;
+    let mut responses =
+        serde_json::Deserializer::from_reader(&stream).into_iter::<kvs::protocol::Response>();
+    assert!(matches!(
+        responses.next().unwrap()?,
+        kvs::protocol::Response::Error(_)
+    ));
+    assert!(responses.next().is_none());
+    Ok(())
+}
+
+// `kvs-client` should set, get and remove keys through a running `kvs-server`.
+#[test]
+fn client_server() -> Result<()> {
+    for engine in Engine::ALL.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let mut server = std::process::Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine.name(), "--addr", &addr])
+            .current_dir(&temp_dir)
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        while std::net::TcpStream::connect(&addr).is_err() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let client = |args: &[&str]| {
+            let mut command = Command::cargo_bin("kvs-client").unwrap();
+            command.args(args).args(&["--addr", &addr]);
+            command
+        };
+        client(&["set", "key1", "value1"])
+            .assert()
+            .success()
+            .stdout(is_empty());
+        client(&["get", "key1"])
+            .assert()
+            .success()
+            .stdout(eq("value1").trim());
+        client(&["rm", "key1"])
+            .assert()
+            .success()
+            .stdout(is_empty());
+        client(&["get", "key1"])
+            .assert()
+            .success()
+            .stdout(eq("Key not found").trim());
+        client(&["rm", "key1"])
+            .assert()
+            .failure()
+            .stdout(eq("Key not found").trim());
+
+        server.kill()?;
+        server.wait()?;
+    }
+    Ok(())
+}
+
+// `kvs-client` should fail cleanly when no server is listening.
+#[test]
+fn client_without_server() -> Result<()> {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", &addr])
+        .assert()
+        .failure();
+    Ok(())
 }